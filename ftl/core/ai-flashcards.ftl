@@ -64,6 +64,14 @@ ai-flashcards-unsupported-file-type = This file type is not yet supported. Pleas
 ai-flashcards-url-fetch-not-implemented = URL fetching is not yet implemented.
 ai-flashcards-api-key-not-configured = OpenAI API key is not configured. Please set it in Preferences.
 ai-flashcards-generation-failed = Flashcard generation failed: { $error }
+ai-flashcards-response-unreadable = The AI response could not be read: { $info }
+ai-flashcards-notetypes-missing = Missing note types: { $names }
+ai-flashcards-deck-not-allowed = Cards can't be imported into this deck.
+ai-flashcards-session-damaged = The AI session file is damaged: { $info }
+ai-flashcards-session-missing = No AI session is stored.
+ai-flashcards-session-unavailable = AI sessions can't be stored for this collection.
+ai-flashcards-session-locked = The AI session is encrypted.
+ai-flashcards-session-finalized = The cards of this AI session have already been imported.
 
 # Preferences
 ai-flashcards-preferences-title = AI Flashcards
@@ -49,6 +49,10 @@ pub fn write_rust_protos(descriptors_path: PathBuf) -> Result<DescriptorPool> {
             "ImportAnkiPackageUpdateCondition",
             "#[derive(serde::Deserialize, serde::Serialize)]",
         )
+        .type_attribute(
+            "ai_flashcards.CardType",
+            "#[derive(serde::Deserialize, serde::Serialize)]",
+        )
         .compile_protos(paths.as_slice(), &[proto_dir])
         .context("prost build")?;
 
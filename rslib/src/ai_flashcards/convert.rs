@@ -0,0 +1,1177 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Conversion of AI-generated cards into notes ready for import.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use anki_proto::ai_flashcards::CardType;
+use regex::Regex;
+use serde::Serialize;
+
+use super::new_session_card_id;
+use super::sanitize::plain_text;
+use super::sanitize::sanitize_card_html;
+use super::sanitize::sanitize_tag;
+use super::sanitize::TagSanitizeConfig;
+use super::validate::cloze_indices;
+use super::validate::normalize_for_compare;
+use super::validate::CLOZE_PATTERN;
+use super::AIGeneratedCard;
+use crate::cloze::cloze_numbers_in_string;
+use crate::import_export::text::ForeignNote;
+use crate::import_export::text::NameOrId;
+use crate::notes::anki_base91;
+use crate::prelude::*;
+use crate::text::strip_html;
+
+/// Tag added to every imported card.
+pub const AI_GENERATED_TAG: &str = "ai-generated";
+
+/// Fields that a card's reference is written to, in order of preference.
+pub const REFERENCE_FIELDS: [&str; 2] = ["Reference", "Extra"];
+
+/// Field that provenance is written to, if the notetype has one.
+pub const AI_META_FIELD: &str = "AI Meta";
+
+/// Field of the "Basic (optional reversed card)" notetype that enables the
+/// reverse card when non-empty.
+pub const ADD_REVERSE_FIELD: &str = "Add Reverse";
+
+/// Field of cloze notetypes that a cloze card's back is written to.
+pub const BACK_EXTRA_FIELD: &str = "Back Extra";
+
+/// Field that a card's notes are written to, if the notetype has one.
+pub const NOTES_FIELD: &str = "Notes";
+
+/// Field that a card's examples are written to, if the notetype has one.
+pub const EXAMPLES_FIELD: &str = "Examples";
+
+/// Written to [ADD_REVERSE_FIELD] if no other marker is configured.
+pub const DEFAULT_ADD_REVERSE_MARKER: &str = "y";
+
+static WHITESPACE_RUN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[ \t]{2,}").unwrap());
+
+/// Where an imported card came from, recorded as JSON in [AI_META_FIELD].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Provenance {
+    pub model: String,
+    pub source: String,
+    /// Unix timestamp of the import.
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConvertConfig {
+    /// Tags added to every card, in addition to the AI-suggested ones.
+    pub additional_tags: Vec<String>,
+    /// Maps AI-suggested tags to a canonical tag, or to `None` to drop them.
+    pub tag_map: HashMap<String, Option<String>>,
+    /// If true, suggested tags not present in `tag_map` are dropped instead
+    /// of being passed through unchanged.
+    pub strict_tag_map: bool,
+    /// Convert newlines in fields into `<br>`, as Anki doesn't display
+    /// plain newlines.
+    pub newlines_to_br: bool,
+    /// Collapse runs of spaces and tabs in fields into a single space.
+    pub collapse_whitespace: bool,
+    /// Remove a pair of double quotes wrapping an entire field, which some
+    /// models add around every value.
+    pub strip_surrounding_quotes: bool,
+    /// Recorded in the [AI_META_FIELD] field of notetypes that have one.
+    pub provenance: Option<Provenance>,
+    pub guid_mode: GuidMode,
+    /// Remove leading and trailing whitespace from the front.
+    pub trim_front: bool,
+    /// Remove leading and trailing whitespace from the back. Usually left
+    /// off for code snippets, where indentation matters.
+    pub trim_back: bool,
+    /// Written to the [ADD_REVERSE_FIELD] of reversed cards, for templates
+    /// that check for a particular value. Defaults to
+    /// [DEFAULT_ADD_REVERSE_MARKER].
+    pub add_reverse_marker: Option<String>,
+    /// Give cloze deletions without a hint one made from the first letter of
+    /// their answer, eg `{{c1::Paris::P...}}`.
+    pub auto_cloze_hint: bool,
+    /// HTML appended to the back of every note (the Extra field of cloze
+    /// notes), such as an attribution. It is added after the card's own
+    /// content is sanitized, so it must be sanitized already; the importer
+    /// does this once per import.
+    pub back_footer_html: Option<String>,
+    /// Tag notes with the provenance's model and date, eg `model::gpt-4o`
+    /// and `generated::2024-06-01`, for analytics.
+    pub provenance_tags: bool,
+}
+
+/// How the GUIDs of imported notes are chosen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GuidMode {
+    /// A new random GUID for every note.
+    #[default]
+    Random,
+    /// A GUID derived from the card's content with [content_guid], so the
+    /// same card gets the same GUID on every machine, and shared decks can
+    /// be merged.
+    Content,
+}
+
+impl ConvertConfig {
+    /// Apply the configured tag map to a single AI-suggested tag.
+    pub fn map_tag(&self, tag: &str) -> Option<String> {
+        match self.tag_map.get(tag) {
+            Some(mapped) => mapped.clone(),
+            None if self.strict_tag_map => None,
+            None => Some(tag.to_string()),
+        }
+    }
+
+    /// Apply the configured cleanup to a field. This runs in
+    /// [to_foreign_note] as cards are imported; cards were already sanitized
+    /// when they were validated, so any `<br>` it introduces is kept.
+    pub fn prepare_field(&self, text: &str) -> String {
+        let mut text = if self.strip_surrounding_quotes {
+            strip_surrounding_quotes(text)
+        } else {
+            text
+        }
+        .to_string();
+        if self.collapse_whitespace {
+            text = WHITESPACE_RUN.replace_all(&text, " ").into_owned();
+        }
+        if self.newlines_to_br {
+            text = text.replace("\r\n", "\n").replace('\n', "<br>");
+        }
+        text
+    }
+
+    /// Write the card's reference to the notetype's reference field, or
+    /// append it to the back if the notetype has no such field. Must be
+    /// called after the other fields are set.
+    pub fn apply_reference(
+        &self,
+        note: &mut Note,
+        notetype: &Notetype,
+        reference: &str,
+    ) -> Result<()> {
+        if reference.trim().is_empty() {
+            return Ok(());
+        }
+        let reference = self.prepare_field(reference);
+        match REFERENCE_FIELDS
+            .iter()
+            .find_map(|name| notetype.get_field_ord(name))
+        {
+            Some(ord) => note.set_field(ord, reference),
+            None => append_to_back(note, notetype, &reference, "<br><br>"),
+        }
+    }
+
+    /// Mark a reversed card's note so its reverse card is generated, if the
+    /// notetype has an [ADD_REVERSE_FIELD] field.
+    pub fn apply_add_reverse(
+        &self,
+        note: &mut Note,
+        notetype: &Notetype,
+        card_type: CardType,
+    ) -> Result<()> {
+        if card_type != CardType::BasicReversed {
+            return Ok(());
+        }
+        if let Some(ord) = notetype.get_field_ord(ADD_REVERSE_FIELD) {
+            let marker = self
+                .add_reverse_marker
+                .as_deref()
+                .unwrap_or(DEFAULT_ADD_REVERSE_MARKER);
+            note.set_field(ord, marker)?;
+        }
+        Ok(())
+    }
+
+    /// Write the card's notes to the notetype's [NOTES_FIELD] field, if it
+    /// has one.
+    pub fn apply_notes(&self, note: &mut Note, notetype: &Notetype, notes: &str) -> Result<()> {
+        if notes.trim().is_empty() {
+            return Ok(());
+        }
+        if let Some(ord) = notetype.get_field_ord(NOTES_FIELD) {
+            note.set_field(ord, self.prepare_field(notes))?;
+        }
+        Ok(())
+    }
+
+    /// The model and date tags of the provenance, if any. The model tag is
+    /// left out if no model was recorded. The date is when the card was
+    /// generated, or of the import if that is unknown.
+    fn tags_from_provenance(&self, card: &AIGeneratedCard) -> Vec<String> {
+        let Some(provenance) = &self.provenance else {
+            return vec![];
+        };
+        let mut tags = vec![];
+        if !provenance.model.trim().is_empty() {
+            let tag = format!("model::{}", provenance.model);
+            tags.extend(sanitize_tag(&tag, &TagSanitizeConfig::default()).tag);
+        }
+        let timestamp = if card.created_timestamp > 0 {
+            card.created_timestamp
+        } else {
+            provenance.timestamp
+        };
+        let date = TimestampSecs(timestamp).date_string();
+        tags.push(format!("generated::{date}"));
+        tags
+    }
+
+    /// Append the configured footer to the note's back. Must be called after
+    /// the other fields, including the reference, are set, so that the footer
+    /// comes last.
+    pub fn apply_back_footer(&self, note: &mut Note, notetype: &Notetype) -> Result<()> {
+        let Some(footer) = self
+            .back_footer_html
+            .as_deref()
+            .filter(|f| !f.trim().is_empty())
+        else {
+            return Ok(());
+        };
+        append_to_back(note, notetype, footer, "<br>")
+    }
+
+    /// Write the card's examples as an HTML list to the notetype's
+    /// [EXAMPLES_FIELD], or append the list to the back if the notetype has
+    /// no such field. Must be called after the other fields are set.
+    pub fn apply_examples(
+        &self,
+        note: &mut Note,
+        notetype: &Notetype,
+        examples: &[String],
+    ) -> Result<()> {
+        let Some(list) = examples_html(examples) else {
+            return Ok(());
+        };
+        match notetype.get_field_ord(EXAMPLES_FIELD) {
+            Some(ord) => note.set_field(ord, list),
+            None => append_to_back(note, notetype, &list, "<br>"),
+        }
+    }
+
+    /// Write the provenance to the note's [AI_META_FIELD] field. Nothing is
+    /// done if no provenance was provided, or the notetype has no such field.
+    pub fn apply_provenance(&self, note: &mut Note, notetype: &Notetype) -> Result<()> {
+        if let Some(provenance) = &self.provenance {
+            if let Some(ord) = notetype.get_field_ord(AI_META_FIELD) {
+                note.set_field(ord, serde_json::to_string(provenance)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Examples as a sanitized HTML list, or `None` if there are none.
+fn examples_html(examples: &[String]) -> Option<String> {
+    let items: String = examples
+        .iter()
+        .map(|example| sanitize_card_html(example))
+        .filter(|example| !example.trim().is_empty())
+        .map(|example| format!("<li>{example}</li>"))
+        .collect();
+    (!items.is_empty()).then(|| format!("<ul>{items}</ul>"))
+}
+
+/// Append `html` to the note's back field, after `separator` if the field
+/// already has content.
+fn append_to_back(note: &mut Note, notetype: &Notetype, html: &str, separator: &str) -> Result<()> {
+    let back_ord = target_field_ords(notetype, 2)[1];
+    match note.fields().get(back_ord) {
+        Some(back) if !back.is_empty() => {
+            let back = format!("{back}{separator}{html}");
+            note.set_field(back_ord, back)
+        }
+        Some(_) => note.set_field(back_ord, html),
+        None => Ok(()),
+    }
+}
+
+/// The name of the stock notetype that cards of the given type are imported
+/// into.
+pub fn default_notetype_name(card_type: CardType) -> &'static str {
+    match card_type {
+        CardType::Basic => "Basic",
+        CardType::BasicReversed => "Basic (and reversed card)",
+        CardType::Cloze => "Cloze",
+    }
+}
+
+/// The notetype field that each of a converted note's fields should be
+/// written to. For notetypes with fields named Front and Back (or Text and
+/// [BACK_EXTRA_FIELD] for cloze notetypes), those are used, as custom
+/// notetypes may not list them first. Otherwise, fields are assigned in order.
+pub fn target_field_ords(notetype: &Notetype, field_count: usize) -> Vec<usize> {
+    if field_count == 2 {
+        let (front, back) = if notetype.is_cloze() {
+            ("Text", BACK_EXTRA_FIELD)
+        } else {
+            ("Front", "Back")
+        };
+        if let (Some(front), Some(back)) =
+            (notetype.get_field_ord(front), notetype.get_field_ord(back))
+        {
+            return vec![front, back];
+        }
+    }
+    (0..field_count).collect()
+}
+
+/// The fields a cloze card has content for that the notetype lacks, so the
+/// user can be warned that the content was put elsewhere or dropped.
+pub fn missing_cloze_fields(card: &AIGeneratedCard, notetype: &Notetype) -> Vec<&'static str> {
+    if card.card_type != CardType::Cloze {
+        return vec![];
+    }
+    let notes = card.notes.as_deref().unwrap_or_default();
+    [(BACK_EXTRA_FIELD, &card.back[..]), (NOTES_FIELD, notes)]
+        .into_iter()
+        .filter(|(field, content)| {
+            !content.trim().is_empty() && notetype.get_field_ord(field).is_none()
+        })
+        .map(|(field, _)| field)
+        .collect()
+}
+
+/// A GUID derived from the card's normalized front and back, ignoring HTML,
+/// case and whitespace differences.
+pub fn content_guid(card: &AIGeneratedCard) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(normalize_for_compare(&card.front).as_bytes());
+    hasher.update(b"\x1f");
+    hasher.update(normalize_for_compare(&card.back).as_bytes());
+    let hash = hasher.finalize();
+    anki_base91(u64::from_be_bytes(hash.as_bytes()[..8].try_into().unwrap()))
+}
+
+/// Strip a single pair of matching double quotes surrounding the text. Text
+/// containing further quotes is left alone, as the outer quotes are then
+/// likely to be part of a legitimate quotation, eg `"a" and "b"`.
+fn strip_surrounding_quotes(text: &str) -> &str {
+    for (open, close) in [('"', '"'), ('\u{201c}', '\u{201d}')] {
+        if let Some(inner) = text
+            .strip_prefix(open)
+            .and_then(|rest| rest.strip_suffix(close))
+        {
+            if !inner.contains([open, close]) {
+                return inner;
+            }
+        }
+    }
+    text
+}
+
+/// Convert an AI-generated card to a ForeignNote for import. The card's
+/// reference is not included, as where it goes depends on the notetype's
+/// fields; see [ConvertConfig::apply_reference].
+pub fn to_foreign_note(
+    card: &AIGeneratedCard,
+    deck_id: DeckId,
+    config: &ConvertConfig,
+) -> ForeignNote {
+    // Combine AI suggested tags with additional tags and auto-tags
+    let mut all_tags: Vec<String> = vec![AI_GENERATED_TAG.to_string()];
+    for tag in card.suggested_tags.iter().filter_map(|t| config.map_tag(t)) {
+        if !all_tags.contains(&tag) {
+            all_tags.push(tag);
+        }
+    }
+    all_tags.extend(config.additional_tags.iter().cloned());
+    if config.provenance_tags {
+        all_tags.extend(config.tags_from_provenance(card));
+    }
+
+    // Create fields based on card type
+    let front = if config.trim_front {
+        card.front.trim()
+    } else {
+        &card.front
+    };
+    let back = if config.trim_back {
+        card.back.trim()
+    } else {
+        &card.back
+    };
+    let fields = match card.card_type {
+        CardType::Cloze => vec![
+            // Text field (with cloze deletions)
+            Some(config.prepare_field(&if config.auto_cloze_hint {
+                add_cloze_hints(front)
+            } else {
+                front.to_string()
+            })),
+            Some(config.prepare_field(back)), // Extra field (usually empty for cloze)
+        ],
+        _ => vec![
+            Some(config.prepare_field(front)), // Front field
+            Some(config.prepare_field(back)),  // Back field
+        ],
+    };
+
+    ForeignNote {
+        guid: match config.guid_mode {
+            GuidMode::Random => String::new(), // Will be auto-generated
+            GuidMode::Content => content_guid(card),
+        },
+        fields,
+        tags: Some(all_tags),
+        notetype: NameOrId::Name(
+            card.notetype
+                .clone()
+                .unwrap_or_else(|| default_notetype_name(card.card_type).to_string()),
+        ),
+        deck: NameOrId::Id(deck_id.0),
+        cards: Vec::new(),
+    }
+}
+
+/// Like [to_foreign_note], but with one field slot per field of the target
+/// notetype, so notetypes with extra fields can be imported into. The content
+/// is placed in the fields chosen by [target_field_ords], and other fields are
+/// left unset, and so empty in new notes.
+pub fn to_foreign_note_for_notetype(
+    card: &AIGeneratedCard,
+    deck_id: DeckId,
+    config: &ConvertConfig,
+    notetype: &Notetype,
+) -> ForeignNote {
+    let mut note = to_foreign_note(card, deck_id, config);
+    note.fields = fields_for_notetype(&note.fields, notetype);
+    note
+}
+
+/// Lay out the fields produced by [to_foreign_note] for the notetype, with a
+/// slot for each of its fields. Content for fields the notetype doesn't have
+/// is dropped.
+pub fn fields_for_notetype(fields: &[Option<String>], notetype: &Notetype) -> Vec<Option<String>> {
+    let mut slots = vec![None; notetype.fields.len()];
+    for (ord, field) in target_field_ords(notetype, fields.len())
+        .into_iter()
+        .zip(fields)
+    {
+        if let Some(slot) = slots.get_mut(ord) {
+            slot.clone_from(field);
+        }
+    }
+    slots
+}
+
+/// Turn a term/definition Basic card into a Cloze card by hiding each
+/// occurrence of the term (the front) within the definition (the back).
+/// Returns `None` if the card isn't Basic, or the term doesn't appear in the
+/// definition.
+pub fn basic_to_cloze_by_term(card: &AIGeneratedCard) -> Option<AIGeneratedCard> {
+    if card.card_type != CardType::Basic {
+        return None;
+    }
+    let term = strip_html(&card.front);
+    let term = term.trim();
+    if term.is_empty() {
+        return None;
+    }
+    // only match whole words, so a term like "cell" doesn't match "cellular"
+    let boundary = |c: Option<char>| {
+        if c.is_some_and(char::is_alphanumeric) {
+            r"\b"
+        } else {
+            ""
+        }
+    };
+    let pattern = format!(
+        "(?i){}{}{}",
+        boundary(term.chars().next()),
+        regex::escape(term),
+        boundary(term.chars().last())
+    );
+    let re = Regex::new(&pattern).ok()?;
+    if !re.is_match(&card.back) {
+        return None;
+    }
+    let text = re.replace_all(&card.back, |caps: &regex::Captures| {
+        format!("{{{{c1::{}}}}}", &caps[0])
+    });
+    Some(AIGeneratedCard {
+        card_type: CardType::Cloze,
+        front: text.into_owned(),
+        back: String::new(),
+        ..card.clone()
+    })
+}
+
+/// Split a BasicReversed card into two Basic cards, one in each direction, for
+/// collections without a reversed notetype. The reverse card's id has
+/// "-reverse" appended. Other cards are returned unchanged.
+pub fn expand_reversed(card: AIGeneratedCard) -> Vec<AIGeneratedCard> {
+    if card.card_type != CardType::BasicReversed {
+        return vec![card];
+    }
+    let forward = AIGeneratedCard {
+        card_type: CardType::Basic,
+        ..card
+    };
+    let reverse = AIGeneratedCard {
+        id: format!("{}-reverse", forward.id),
+        front: forward.back.clone(),
+        back: forward.front.clone(),
+        ..forward.clone()
+    };
+    vec![forward, reverse]
+}
+
+/// Add a hint of the answer's first letter to every cloze deletion that has
+/// no hint. Deletions whose answer has no visible text are left alone.
+pub fn add_cloze_hints(text: &str) -> String {
+    CLOZE_PATTERN
+        .replace_all(text, |caps: &regex::Captures| {
+            let first_letter = plain_text(&caps[2]).chars().next();
+            match first_letter {
+                Some(letter) if caps.get(3).is_none() => {
+                    format!("{{{{c{}::{}::{letter}...}}}}", &caps[1], &caps[2])
+                }
+                _ => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Split a cloze card with more than `max_deletions` distinct cloze numbers
+/// into several cards, each keeping up to that many numbers as deletions and
+/// showing the answers of the others as plain text. Numbering is kept, and
+/// the parts' ids have "-part1", "-part2" etc appended. Other cards are
+/// returned unchanged.
+pub fn split_cloze_card(card: AIGeneratedCard, max_deletions: usize) -> Vec<AIGeneratedCard> {
+    let indices = cloze_indices(&card.front);
+    if card.card_type != CardType::Cloze || max_deletions == 0 || indices.len() <= max_deletions {
+        return vec![card];
+    }
+    indices
+        .chunks(max_deletions)
+        .enumerate()
+        .map(|(idx, kept)| AIGeneratedCard {
+            id: format!("{}-part{}", card.id, idx + 1),
+            front: CLOZE_PATTERN
+                .replace_all(&card.front, |caps: &regex::Captures| {
+                    let kept = caps[1].parse().is_ok_and(|n: u32| kept.contains(&n));
+                    if kept {
+                        caps[0].to_string()
+                    } else {
+                        caps[2].to_string()
+                    }
+                })
+                .into_owned(),
+            ..card.clone()
+        })
+        .collect()
+}
+
+/// Build cards from existing question/answer pairs, so they can be validated
+/// and imported like generated ones. For cloze cards, the question is the
+/// cloze text and the answer becomes the extra text.
+pub fn cards_from_qa_pairs(
+    pairs: Vec<(String, String)>,
+    card_type: CardType,
+) -> Vec<AIGeneratedCard> {
+    pairs
+        .into_iter()
+        .map(|(front, back)| AIGeneratedCard {
+            id: new_session_card_id(),
+            card_type,
+            front,
+            back,
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// The card's front as plain text for display in lists, with whitespace
+/// collapsed. Text longer than `max_chars` is cut at that length, and an
+/// ellipsis is appended.
+pub fn front_preview(card: &AIGeneratedCard, max_chars: usize) -> String {
+    let text = plain_text(&card.front);
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+    let truncated: String = text.chars().take(max_chars).collect();
+    format!("{}…", truncated.trim_end())
+}
+
+/// A deck name for cards generated from the named source, such as a file
+/// name. Any folders and extension are removed, and `::` is replaced so the
+/// name doesn't create subdecks. Returns `None` if nothing usable remains.
+pub fn deck_name_from_source(source_name: &str) -> Option<String> {
+    let stem = Path::new(source_name.trim())
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let name = stem
+        .replace("::", " ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!name.is_empty()).then_some(name)
+}
+
+/// Estimate how many Anki cards importing the provided cards would create.
+/// Each distinct cloze number creates a card, and reversed notes create two.
+pub fn estimate_generated_cards(cards: &[AIGeneratedCard]) -> usize {
+    cards
+        .iter()
+        .map(|card| match card.card_type {
+            CardType::Basic => 1,
+            CardType::BasicReversed => 2,
+            CardType::Cloze => cloze_numbers_in_string(&card.front).len(),
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use anki_proto::ai_flashcards::CardStatus;
+
+    use super::*;
+    use crate::ai_flashcards::validate::validate_card;
+    use crate::ai_flashcards::validate::ValidationConfig;
+
+    fn card_with_tags(tags: &[&str]) -> AIGeneratedCard {
+        AIGeneratedCard {
+            id: "1".to_string(),
+            card_type: CardType::Basic,
+            front: "Q".to_string(),
+            back: "A".to_string(),
+            suggested_tags: tags.iter().map(ToString::to_string).collect(),
+            status: CardStatus::Approved,
+            ..Default::default()
+        }
+    }
+
+    fn tag_map(entries: &[(&str, Option<&str>)]) -> HashMap<String, Option<String>> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.map(ToString::to_string)))
+            .collect()
+    }
+
+    fn converted_tags(card: &AIGeneratedCard, config: &ConvertConfig) -> Vec<String> {
+        to_foreign_note(card, DeckId(1), config).tags.unwrap()
+    }
+
+    #[test]
+    fn tags_are_mapped_to_canonical_form() {
+        let config = ConvertConfig {
+            tag_map: tag_map(&[("bio", Some("Biology")), ("cells", Some("Biology"))]),
+            ..Default::default()
+        };
+        let card = card_with_tags(&["bio", "cells", "other"]);
+        assert_eq!(
+            converted_tags(&card, &config),
+            ["ai-generated", "Biology", "other"]
+        );
+    }
+
+    #[test]
+    fn tags_mapped_to_none_are_dropped() {
+        let config = ConvertConfig {
+            tag_map: tag_map(&[("misc", None)]),
+            ..Default::default()
+        };
+        let card = card_with_tags(&["misc", "chemistry"]);
+        assert_eq!(
+            converted_tags(&card, &config),
+            ["ai-generated", "chemistry"]
+        );
+    }
+
+    #[test]
+    fn strict_mode_drops_unmapped_tags() {
+        let config = ConvertConfig {
+            additional_tags: vec!["extra".to_string()],
+            tag_map: tag_map(&[("bio", Some("Biology"))]),
+            strict_tag_map: true,
+            ..Default::default()
+        };
+        let card = card_with_tags(&["bio", "unknown"]);
+        // additional tags are user-supplied, so they're not filtered
+        assert_eq!(
+            converted_tags(&card, &config),
+            ["ai-generated", "Biology", "extra"]
+        );
+    }
+
+    #[test]
+    fn newlines_can_become_line_breaks() {
+        let mut card = card_with_tags(&[]);
+        card.back = "line one\nline two\r\nline three".to_string();
+        let config = ConvertConfig {
+            newlines_to_br: true,
+            ..Default::default()
+        };
+        let note = to_foreign_note(&card, DeckId(1), &config);
+        assert_eq!(
+            note.fields[1].as_deref(),
+            Some("line one<br>line two<br>line three")
+        );
+        // off by default
+        let note = to_foreign_note(&card, DeckId(1), &ConvertConfig::default());
+        assert_eq!(note.fields[1].as_ref(), Some(&card.back));
+    }
+
+    #[test]
+    fn whitespace_runs_can_be_collapsed() {
+        let config = ConvertConfig {
+            collapse_whitespace: true,
+            ..Default::default()
+        };
+        assert_eq!(config.prepare_field("a  b \t\t c d"), "a b c d");
+        assert_eq!(ConvertConfig::default().prepare_field("a  b"), "a  b");
+    }
+
+    #[test]
+    fn generated_cards_are_estimated_per_card_type() {
+        let card = |card_type, front: &str| AIGeneratedCard {
+            card_type,
+            front: front.to_string(),
+            ..card_with_tags(&[])
+        };
+        let basic = card(CardType::Basic, "Q");
+        let reversed = card(CardType::BasicReversed, "Q");
+        let cloze = card(CardType::Cloze, "{{c1::a}} {{c2::b}} {{c1::c}} {{c3::d}}");
+        assert_eq!(estimate_generated_cards(&[basic.clone()]), 1);
+        assert_eq!(estimate_generated_cards(&[reversed.clone()]), 2);
+        assert_eq!(estimate_generated_cards(&[cloze.clone()]), 3);
+        assert_eq!(estimate_generated_cards(&[basic, reversed, cloze]), 6);
+        assert_eq!(estimate_generated_cards(&[]), 0);
+    }
+
+    #[test]
+    fn surrounding_quotes_can_be_stripped() {
+        let config = ConvertConfig {
+            strip_surrounding_quotes: true,
+            ..Default::default()
+        };
+        assert_eq!(config.prepare_field("\"What is Rust?\""), "What is Rust?");
+        assert_eq!(config.prepare_field("\u{201c}Hello\u{201d}"), "Hello");
+        // quotations within the text are kept
+        assert_eq!(
+            config.prepare_field("\"To be\" or \"not to be\""),
+            "\"To be\" or \"not to be\""
+        );
+        assert_eq!(config.prepare_field("\"unbalanced"), "\"unbalanced");
+        assert_eq!(config.prepare_field("\""), "\"");
+        assert_eq!(
+            ConvertConfig::default().prepare_field("\"quoted\""),
+            "\"quoted\""
+        );
+    }
+
+    #[test]
+    fn definitions_can_become_clozes() {
+        let card = AIGeneratedCard {
+            front: "Mitochondria".to_string(),
+            back: "mitochondria produce energy; Mitochondria have their own DNA".to_string(),
+            ..card_with_tags(&["bio"])
+        };
+        let cloze = basic_to_cloze_by_term(&card).unwrap();
+        assert_eq!(cloze.card_type, CardType::Cloze);
+        assert_eq!(
+            cloze.front,
+            "{{c1::mitochondria}} produce energy; {{c1::Mitochondria}} have their own DNA"
+        );
+        assert_eq!(cloze.back, "");
+        assert_eq!(cloze.suggested_tags, ["bio"]);
+
+        // partial words aren't matched
+        let card = AIGeneratedCard {
+            front: "cell".to_string(),
+            back: "cellular respiration".to_string(),
+            ..card_with_tags(&[])
+        };
+        assert_eq!(basic_to_cloze_by_term(&card), None);
+    }
+
+    #[test]
+    fn card_types_map_to_stock_notetypes() {
+        assert_eq!(default_notetype_name(CardType::Basic), "Basic");
+        assert_eq!(
+            default_notetype_name(CardType::BasicReversed),
+            "Basic (and reversed card)"
+        );
+        assert_eq!(default_notetype_name(CardType::Cloze), "Cloze");
+    }
+
+    #[test]
+    fn reference_goes_to_reference_field_or_back() {
+        let mut col = Collection::new();
+        let config = ConvertConfig::default();
+        let basic = col.get_notetype_by_name("Basic").unwrap().unwrap();
+        let mut note = basic.new_note();
+        note.set_field(1, "answer").unwrap();
+        config
+            .apply_reference(&mut note, &basic, "Chapter 2")
+            .unwrap();
+        assert_eq!(note.fields()[1], "answer<br><br>Chapter 2");
+
+        let mut with_field = (*basic).clone();
+        with_field.add_field("Reference");
+        let mut note = with_field.new_note();
+        note.set_field(1, "answer").unwrap();
+        config
+            .apply_reference(&mut note, &with_field, "Chapter 2")
+            .unwrap();
+        assert_eq!(note.fields()[1], "answer");
+        assert_eq!(note.fields()[2], "Chapter 2");
+
+        // an empty reference leaves the note unchanged
+        let mut note = basic.new_note();
+        config.apply_reference(&mut note, &basic, " ").unwrap();
+        assert_eq!(note.fields()[1], "");
+    }
+
+    #[test]
+    fn cards_can_be_built_from_pairs() {
+        let pairs = vec![
+            ("Capital of France?".to_string(), "Paris".to_string()),
+            ("Capital of Spain?".to_string(), "Madrid".to_string()),
+            ("Capital of Italy?".to_string(), String::new()),
+        ];
+        let cards = cards_from_qa_pairs(pairs, CardType::Basic);
+        assert_eq!(cards.len(), 3);
+        assert_ne!(cards[0].id, cards[1].id);
+        assert!(cards
+            .iter()
+            .all(|card| card.card_type == CardType::Basic && card.status == CardStatus::Pending));
+
+        let config = ValidationConfig::default();
+        let valid: Vec<bool> = cards
+            .iter()
+            .map(|card| validate_card(card, &config).valid)
+            .collect();
+        assert_eq!(valid, [true, true, false]);
+    }
+
+    #[test]
+    fn explicit_notetype_is_preferred() {
+        let card = AIGeneratedCard {
+            notetype: Some("Vocabulary".to_string()),
+            ..card_with_tags(&[])
+        };
+        let note = to_foreign_note(&card, DeckId(1), &ConvertConfig::default());
+        assert_eq!(note.notetype, NameOrId::Name("Vocabulary".to_string()));
+        let note = to_foreign_note(&card_with_tags(&[]), DeckId(1), &ConvertConfig::default());
+        assert_eq!(note.notetype, NameOrId::Name("Basic".to_string()));
+    }
+
+    #[test]
+    fn content_guids_are_stable() {
+        let card = card_with_tags(&[]);
+        let same = AIGeneratedCard {
+            id: "2".to_string(),
+            front: " <b>q</b>".to_string(),
+            ..card_with_tags(&["other"])
+        };
+        let different = AIGeneratedCard {
+            back: "B".to_string(),
+            ..card_with_tags(&[])
+        };
+        assert_eq!(content_guid(&card), content_guid(&same));
+        assert_ne!(content_guid(&card), content_guid(&different));
+
+        let config = ConvertConfig {
+            guid_mode: GuidMode::Content,
+            ..Default::default()
+        };
+        assert_eq!(
+            to_foreign_note(&card, DeckId(1), &config).guid,
+            content_guid(&card)
+        );
+        let note = to_foreign_note(&card, DeckId(1), &ConvertConfig::default());
+        assert!(note.guid.is_empty());
+    }
+
+    #[test]
+    fn front_previews_are_plain_and_short() {
+        let card = |front: &str| AIGeneratedCard {
+            front: front.to_string(),
+            ..card_with_tags(&[])
+        };
+        assert_eq!(
+            front_preview(&card("<b>What</b> is\n<i>ATP</i>&amp;co?"), 50),
+            "What is ATP&co?"
+        );
+        assert_eq!(
+            front_preview(&card("The quick brown fox"), 10),
+            "The quick…"
+        );
+        assert_eq!(front_preview(&card("exactly"), 7), "exactly");
+        assert_eq!(front_preview(&card("<img src=a.png>"), 10), "");
+    }
+
+    #[test]
+    fn deck_names_are_derived_from_source() {
+        assert_eq!(
+            deck_name_from_source("/home/user/Cell Biology.pdf").as_deref(),
+            Some("Cell Biology")
+        );
+        assert_eq!(
+            deck_name_from_source("a::b  c.txt").as_deref(),
+            Some("a b c")
+        );
+        assert_eq!(deck_name_from_source(" "), None);
+    }
+
+    #[test]
+    fn cloze_hints_are_added_where_missing() {
+        let card = AIGeneratedCard {
+            card_type: CardType::Cloze,
+            front: "{{c1::Paris}} is in {{c2::France::country}}, near {{c3::<b>Lyon</b>}}"
+                .to_string(),
+            ..Default::default()
+        };
+        let config = ConvertConfig {
+            auto_cloze_hint: true,
+            ..Default::default()
+        };
+        let note = to_foreign_note(&card, DeckId(1), &config);
+        assert_eq!(
+            note.fields[0].as_deref(),
+            Some(
+                "{{c1::Paris::P...}} is in {{c2::France::country}}, near {{c3::<b>Lyon</b>::L...}}"
+            )
+        );
+        assert_eq!(
+            add_cloze_hints("{{c1::<img src=a.png>}}"),
+            "{{c1::<img src=a.png>}}"
+        );
+
+        // off by default
+        let note = to_foreign_note(&card, DeckId(1), &ConvertConfig::default());
+        assert_eq!(note.fields[0].as_ref(), Some(&card.front));
+    }
+
+    #[test]
+    fn cloze_cards_can_be_split() {
+        let card = AIGeneratedCard {
+            id: "1".to_string(),
+            card_type: CardType::Cloze,
+            front: "{{c1::a}} {{c2::b::hint}} {{c3::c}} {{c1::d}}".to_string(),
+            ..Default::default()
+        };
+        let parts = split_cloze_card(card.clone(), 2);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].id, "1-part1");
+        assert_eq!(parts[0].front, "{{c1::a}} {{c2::b::hint}} c {{c1::d}}");
+        assert_eq!(parts[1].front, "a b {{c3::c}} d");
+        assert_eq!(split_cloze_card(card, 3).len(), 1);
+    }
+
+    #[test]
+    fn reversed_cards_can_be_expanded() {
+        let reversed = AIGeneratedCard {
+            card_type: CardType::BasicReversed,
+            ..card_with_tags(&["bio"])
+        };
+        let expanded = expand_reversed(reversed);
+        assert_eq!(expanded.len(), 2);
+        assert!(expanded.iter().all(|c| c.card_type == CardType::Basic));
+        assert_eq!(
+            (expanded[0].front.as_str(), expanded[0].back.as_str()),
+            ("Q", "A")
+        );
+        assert_eq!(
+            (expanded[1].front.as_str(), expanded[1].back.as_str()),
+            ("A", "Q")
+        );
+        assert_eq!(expanded[1].id, "1-reverse");
+        assert_eq!(expanded[1].suggested_tags, ["bio"]);
+        assert_eq!(expand_reversed(card_with_tags(&[])).len(), 1);
+    }
+
+    #[test]
+    fn fields_can_be_trimmed_separately() {
+        let card = AIGeneratedCard {
+            front: "  What does this print?\n".to_string(),
+            back: "    print(1)\n".to_string(),
+            ..card_with_tags(&[])
+        };
+        let config = ConvertConfig {
+            trim_front: true,
+            ..Default::default()
+        };
+        let note = to_foreign_note(&card, DeckId(1), &config);
+        assert_eq!(note.fields[0].as_deref(), Some("What does this print?"));
+        assert_eq!(note.fields[1].as_deref(), Some("    print(1)\n"));
+
+        // nothing is trimmed by default
+        let note = to_foreign_note(&card, DeckId(1), &ConvertConfig::default());
+        assert_eq!(note.fields[0].as_ref(), Some(&card.front));
+    }
+
+    #[test]
+    fn reversed_cards_set_add_reverse_marker() {
+        let mut col = Collection::new();
+        let optional = col
+            .get_notetype_by_name("Basic (optional reversed card)")
+            .unwrap()
+            .unwrap();
+        let config = ConvertConfig {
+            add_reverse_marker: Some("1".to_string()),
+            ..Default::default()
+        };
+        let mut note = optional.new_note();
+        config
+            .apply_add_reverse(&mut note, &optional, CardType::BasicReversed)
+            .unwrap();
+        assert_eq!(note.fields()[2], "1");
+
+        let mut note = optional.new_note();
+        ConvertConfig::default()
+            .apply_add_reverse(&mut note, &optional, CardType::BasicReversed)
+            .unwrap();
+        assert_eq!(note.fields()[2], DEFAULT_ADD_REVERSE_MARKER);
+
+        // basic cards stay one-sided
+        let mut note = optional.new_note();
+        config
+            .apply_add_reverse(&mut note, &optional, CardType::Basic)
+            .unwrap();
+        assert_eq!(note.fields()[2], "");
+    }
+
+    #[test]
+    fn cloze_extra_fields_are_mapped_by_name() {
+        let mut col = Collection::new();
+        let mut notetype = col.get_notetype_by_name("Cloze").unwrap().unwrap();
+        let card = AIGeneratedCard {
+            card_type: CardType::Cloze,
+            front: "{{c1::Paris}} is the capital of France".to_string(),
+            back: "Population: 2 million".to_string(),
+            notes: Some("See chapter 3".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(missing_cloze_fields(&card, &notetype), [NOTES_FIELD]);
+
+        // a complex cloze notetype with the extra fields in another order
+        let mut nt = (*notetype).clone();
+        nt.add_field(NOTES_FIELD);
+        nt.fields.rotate_right(1);
+        notetype = nt.into();
+        assert!(missing_cloze_fields(&card, &notetype).is_empty());
+
+        let foreign = to_foreign_note(&card, DeckId(1), &ConvertConfig::default());
+        let mut note = notetype.new_note();
+        let ords = target_field_ords(&notetype, foreign.fields.len());
+        for (ord, field) in ords.into_iter().zip(foreign.fields) {
+            note.set_field(ord, field.unwrap()).unwrap();
+        }
+        ConvertConfig::default()
+            .apply_notes(&mut note, &notetype, card.notes.as_deref().unwrap())
+            .unwrap();
+        let field = |name: &str| &note.fields()[notetype.get_field_ord(name).unwrap()];
+        assert_eq!(field("Text"), &card.front);
+        assert_eq!(field(BACK_EXTRA_FIELD), &card.back);
+        assert_eq!(field(NOTES_FIELD), "See chapter 3");
+    }
+
+    #[test]
+    fn examples_are_written_as_a_list() {
+        let mut col = Collection::new();
+        let basic = col.get_notetype_by_name("Basic").unwrap().unwrap();
+        let examples = vec![
+            "Mi casa es grande.".to_string(),
+            "<b>La</b> casa<script>x()</script>".to_string(),
+        ];
+        let list = "<ul><li>Mi casa es grande.</li><li><b>La</b> casa</li></ul>";
+
+        // appended to the back if there is no examples field
+        let mut note = basic.new_note();
+        note.set_field(1, "the house").unwrap();
+        ConvertConfig::default()
+            .apply_examples(&mut note, &basic, &examples)
+            .unwrap();
+        assert_eq!(note.fields()[1], format!("the house<br>{list}"));
+
+        let mut nt = (*basic).clone();
+        nt.add_field(EXAMPLES_FIELD);
+        let mut note = nt.new_note();
+        note.set_field(1, "the house").unwrap();
+        ConvertConfig::default()
+            .apply_examples(&mut note, &nt, &examples)
+            .unwrap();
+        assert_eq!(note.fields()[1], "the house");
+        assert_eq!(note.fields()[2], list);
+
+        // nothing is written without examples
+        ConvertConfig::default()
+            .apply_examples(&mut note, &nt, &[])
+            .unwrap();
+        assert_eq!(note.fields()[2], list);
+    }
+
+    #[test]
+    fn fields_are_laid_out_for_the_notetype() {
+        let mut col = Collection::new();
+        let basic = col.get_notetype_by_name("Basic").unwrap().unwrap();
+        // a Basic variant with an extra field before the standard ones
+        let mut notetype = (*basic).clone();
+        notetype.add_field("Image");
+        notetype.fields.rotate_right(1);
+        let card = AIGeneratedCard {
+            front: "Q".to_string(),
+            back: "A".to_string(),
+            ..Default::default()
+        };
+
+        let note =
+            to_foreign_note_for_notetype(&card, DeckId(1), &ConvertConfig::default(), &notetype);
+        assert_eq!(
+            note.fields,
+            [None, Some("Q".to_string()), Some("A".to_string())]
+        );
+    }
+
+    #[test]
+    fn provenance_can_be_recorded_as_tags() {
+        let timestamp = 1717243200;
+        let mut config = ConvertConfig {
+            provenance: Some(Provenance {
+                model: "gpt-4o".to_string(),
+                source: "notes.pdf".to_string(),
+                timestamp,
+            }),
+            ..Default::default()
+        };
+        let card = AIGeneratedCard {
+            front: "Q".to_string(),
+            back: "A".to_string(),
+            ..Default::default()
+        };
+        let tags = |config: &ConvertConfig| to_foreign_note(&card, DeckId(1), config).tags.unwrap();
+        assert_eq!(tags(&config), [AI_GENERATED_TAG]);
+
+        config.provenance_tags = true;
+        let date = TimestampSecs(timestamp).date_string();
+        assert_eq!(
+            tags(&config),
+            [
+                AI_GENERATED_TAG.to_string(),
+                "model::gpt-4o".to_string(),
+                format!("generated::{date}")
+            ]
+        );
+
+        // the card's generation date is preferred to the import's
+        let generated = timestamp - 12 * 86_400;
+        let card = AIGeneratedCard {
+            created_timestamp: generated,
+            ..card.clone()
+        };
+        let tags = to_foreign_note(&card, DeckId(1), &config).tags.unwrap();
+        let date = TimestampSecs(generated).date_string();
+        assert_eq!(tags.last().unwrap(), &format!("generated::{date}"));
+        assert_ne!(date, TimestampSecs(timestamp).date_string());
+    }
+}
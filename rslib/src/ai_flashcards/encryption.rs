@@ -0,0 +1,176 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Optional passphrase-based encryption of the session file.
+//!
+//! A key is derived from the passphrase with PBKDF2, and the content is
+//! encrypted with ring's ChaCha20-Poly1305, so a wrong passphrase or a
+//! tampered file is detected before any content is parsed. Each file has its
+//! own random salt, and thus its own key, so random nonces can't repeat under
+//! the same key.
+//!
+//! Encrypted files start with [ENCRYPTED_HEADER], followed by the PBKDF2
+//! round count (big-endian u32), salt, nonce and ciphertext. The header, round
+//! count and salt are authenticated along with the content. Storing the round
+//! count means it can be raised later without breaking existing files.
+//! Plaintext session files are JSON, so the two can't be confused.
+//!
+//! Only loading and saving the whole session accept a passphrase. The helpers
+//! that modify the stored session in place fail with
+//! [SessionLocked](super::AiFlashcardsError::SessionLocked) when it is
+//! encrypted, and an encrypted session is never considered finalized.
+
+use pbkdf2::password_hash::PasswordHasher;
+use pbkdf2::password_hash::SaltString;
+use pbkdf2::Params;
+use pbkdf2::Pbkdf2;
+use ring::aead::Aad;
+use ring::aead::LessSafeKey;
+use ring::aead::Nonce;
+use ring::aead::UnboundKey;
+use ring::aead::CHACHA20_POLY1305;
+use ring::aead::NONCE_LEN;
+
+use crate::prelude::*;
+
+/// Identifies an encrypted session file, and the version of its format.
+pub(super) const ENCRYPTED_HEADER: &[u8] = b"anki-ai-session-encrypted-v3\n";
+
+/// PBKDF2-HMAC-SHA256 rounds used for new files.
+const PBKDF2_ROUNDS: u32 = 600_000;
+/// Files asking for more rounds than this are rejected, so a modified file
+/// can't make opening it take arbitrarily long.
+const MAX_PBKDF2_ROUNDS: u32 = 10 * PBKDF2_ROUNDS;
+
+const ROUNDS_LEN: usize = 4;
+const SALT_LEN: usize = 16;
+
+pub(super) fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_HEADER)
+}
+
+pub(super) fn encrypt(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let nonce: [u8; NONCE_LEN] = rand::random();
+
+    let mut out = Vec::with_capacity(
+        ENCRYPTED_HEADER.len()
+            + ROUNDS_LEN
+            + SALT_LEN
+            + NONCE_LEN
+            + plaintext.len()
+            + CHACHA20_POLY1305.tag_len(),
+    );
+    out.extend_from_slice(ENCRYPTED_HEADER);
+    out.extend_from_slice(&PBKDF2_ROUNDS.to_be_bytes());
+    out.extend_from_slice(&salt);
+
+    let key = session_key(passphrase, PBKDF2_ROUNDS, &salt)?;
+    let mut ciphertext = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(&out),
+        &mut ciphertext,
+    )
+    .ok()
+    .or_invalid("unable to encrypt session")?;
+
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypt a file produced by [encrypt]. Returns `None` if the passphrase is
+/// wrong or the file has been modified.
+pub(super) fn decrypt(data: &[u8], passphrase: &str) -> Result<Option<Vec<u8>>> {
+    let Some(body) = data.strip_prefix(ENCRYPTED_HEADER) else {
+        invalid_input!("session file is not encrypted");
+    };
+    // sessions are never encrypted with an empty passphrase
+    if passphrase.is_empty() || body.len() < ROUNDS_LEN + SALT_LEN + NONCE_LEN {
+        return Ok(None);
+    }
+    let (rounds, rest) = body.split_at(ROUNDS_LEN);
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let rounds = u32::from_be_bytes(rounds.try_into().unwrap());
+    if rounds == 0 || rounds > MAX_PBKDF2_ROUNDS {
+        return Ok(None);
+    }
+    let aad = &data[..ENCRYPTED_HEADER.len() + ROUNDS_LEN + SALT_LEN];
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .ok()
+        .or_invalid("invalid session nonce")?;
+
+    let key = session_key(passphrase, rounds, salt)?;
+    let mut plaintext = ciphertext.to_vec();
+    let Ok(len) = key
+        .open_in_place(nonce, Aad::from(aad), &mut plaintext)
+        .map(|plaintext| plaintext.len())
+    else {
+        return Ok(None);
+    };
+    plaintext.truncate(len);
+    Ok(Some(plaintext))
+}
+
+/// Derive the session key from the passphrase with the provided number of
+/// PBKDF2 rounds.
+fn session_key(passphrase: &str, rounds: u32, salt: &[u8]) -> Result<LessSafeKey> {
+    let salt = SaltString::encode_b64(salt)
+        .ok()
+        .or_invalid("invalid session salt")?;
+    let params = Params {
+        rounds,
+        output_length: 32,
+    };
+    let hash = Pbkdf2
+        .hash_password_customized(passphrase.as_bytes(), None, None, params, &salt)
+        .ok()
+        .and_then(|hash| hash.hash)
+        .or_invalid("unable to derive session key")?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, hash.as_bytes())
+        .ok()
+        .or_invalid("invalid session key")?;
+    Ok(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let plaintext = br#"{"cards":[]}"#;
+        let encrypted = encrypt(plaintext, "secret").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(plaintext));
+        assert!(!encrypted.windows(plaintext.len()).any(|w| w == plaintext));
+        assert_eq!(
+            decrypt(&encrypted, "secret").unwrap().unwrap(),
+            plaintext.to_vec()
+        );
+    }
+
+    #[test]
+    fn wrong_passphrase_or_tampering_is_detected() {
+        let mut encrypted = encrypt(b"content", "secret").unwrap();
+        assert_eq!(decrypt(&encrypted, "wrong").unwrap(), None);
+        assert_eq!(decrypt(&encrypted, "").unwrap(), None);
+        *encrypted.last_mut().unwrap() ^= 1;
+        assert_eq!(decrypt(&encrypted, "secret").unwrap(), None);
+        assert_eq!(decrypt(ENCRYPTED_HEADER, "secret").unwrap(), None);
+    }
+
+    #[test]
+    fn round_count_is_authenticated() {
+        let mut encrypted = encrypt(b"content", "secret").unwrap();
+        let rounds_start = ENCRYPTED_HEADER.len();
+        assert_eq!(
+            encrypted[rounds_start..rounds_start + ROUNDS_LEN],
+            PBKDF2_ROUNDS.to_be_bytes()
+        );
+        encrypted[rounds_start + ROUNDS_LEN - 1] ^= 1;
+        assert_eq!(decrypt(&encrypted, "secret").unwrap(), None);
+    }
+}
@@ -0,0 +1,50 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use snafu::Snafu;
+
+use crate::prelude::*;
+
+/// Errors specific to AI flashcards, so callers can tell their causes apart.
+#[derive(Debug, PartialEq, Eq, Clone, Snafu)]
+pub enum AiFlashcardsError {
+    /// The model's response didn't contain cards in the expected format.
+    ParseFailed { info: String },
+    /// The cards or session didn't meet a requirement of the operation.
+    ValidationFailed { info: String },
+    /// Notetypes the cards are to be imported into don't exist.
+    NotetypeMissing { names: Vec<String> },
+    /// The import's deck allowlist doesn't include the target deck.
+    DeckNotAllowed { deck_id: DeckId },
+    /// The stored session file couldn't be read.
+    SessionCorrupt { info: String },
+    /// The operation requires a stored session, and there is none.
+    SessionMissing,
+    /// The collection has no folder to store the session in, as with
+    /// in-memory collections.
+    SessionUnavailable,
+    /// The stored session is encrypted, and no passphrase was provided.
+    SessionLocked,
+    /// The session's cards were already imported, and importing them again
+    /// wasn't explicitly allowed.
+    SessionFinalized,
+}
+
+impl AiFlashcardsError {
+    pub(crate) fn message(&self, tr: &I18n) -> String {
+        match self {
+            AiFlashcardsError::ParseFailed { info } => tr.ai_flashcards_response_unreadable(info),
+            AiFlashcardsError::ValidationFailed { info } => info.into(),
+            AiFlashcardsError::NotetypeMissing { names } => {
+                tr.ai_flashcards_notetypes_missing(names.join(", "))
+            }
+            AiFlashcardsError::DeckNotAllowed { .. } => tr.ai_flashcards_deck_not_allowed(),
+            AiFlashcardsError::SessionCorrupt { info } => tr.ai_flashcards_session_damaged(info),
+            AiFlashcardsError::SessionMissing => tr.ai_flashcards_session_missing(),
+            AiFlashcardsError::SessionUnavailable => tr.ai_flashcards_session_unavailable(),
+            AiFlashcardsError::SessionLocked => tr.ai_flashcards_session_locked(),
+            AiFlashcardsError::SessionFinalized => tr.ai_flashcards_session_finalized(),
+        }
+        .into()
+    }
+}
@@ -7,4 +7,152 @@
 //! The actual AI operations (generation, cost estimation) are
 //! implemented in Python (pylib/anki/ai_flashcards/).
 
+pub mod convert;
+mod encryption;
+mod error;
+pub mod parse;
+pub mod sanitize;
 mod service;
+mod session;
+pub mod settings;
+mod tsv;
+pub mod validate;
+
+pub use error::AiFlashcardsError;
+pub use service::AIImportOptions;
+pub use service::ClozeOverflow;
+pub use service::MediaSource;
+pub use service::OnDuplicate;
+pub use service::ReversedFallback;
+pub use session::diff_sessions;
+pub use session::new_session_card_id;
+pub use session::session_version;
+pub use session::SessionCard;
+pub use session::SessionDiff;
+pub use session::SessionFile;
+pub use tsv::cards_from_tsv;
+pub use tsv::import_session_tsv;
+
+use anki_proto::ai_flashcards::CardStatus;
+use anki_proto::ai_flashcards::CardType;
+use anki_proto::ai_flashcards::GeneratedCard;
+
+/// A card produced by the model, decoupled from its protobuf representation
+/// so it can be validated and converted on the Rust side.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AIGeneratedCard {
+    pub id: String,
+    pub card_type: CardType,
+    /// Front content (or full cloze text for cloze cards)
+    pub front: String,
+    /// Back content (extra text for cloze cards)
+    pub back: String,
+    pub suggested_tags: Vec<String>,
+    pub status: CardStatus,
+    /// The model's confidence in the card, from 0 to 1, if it provided one
+    pub confidence: Option<f32>,
+    /// Supporting text, such as a source excerpt, kept apart from the answer
+    pub reference: String,
+    /// The notetype to import the card into, instead of the default for its
+    /// type
+    pub notetype: Option<String>,
+    /// Further notes, written to the notetype's Notes field if it has one
+    pub notes: Option<String>,
+    /// Example sentences, written to the notetype's Examples field if it has
+    /// one, or appended to the back
+    pub examples: Vec<String>,
+    /// Unix timestamp of when the card was generated, or 0 if unknown
+    pub created_timestamp: i64,
+}
+
+impl From<GeneratedCard> for AIGeneratedCard {
+    fn from(card: GeneratedCard) -> Self {
+        AIGeneratedCard {
+            card_type: card.card_type(),
+            status: card.status(),
+            id: card.id,
+            front: card.front,
+            back: card.back,
+            suggested_tags: card.suggested_tags,
+            confidence: card.confidence,
+            reference: card.reference,
+            notetype: card.notetype,
+            notes: card.notes,
+            examples: card.examples,
+            created_timestamp: card.created_timestamp,
+        }
+    }
+}
+
+impl From<AIGeneratedCard> for GeneratedCard {
+    fn from(card: AIGeneratedCard) -> Self {
+        GeneratedCard {
+            id: card.id,
+            card_type: card.card_type.into(),
+            front: card.front,
+            back: card.back,
+            suggested_tags: card.suggested_tags,
+            status: card.status.into(),
+            confidence: card.confidence,
+            reference: card.reference,
+            notetype: card.notetype,
+            notes: card.notes,
+            examples: card.examples,
+            created_timestamp: card.created_timestamp,
+            ..Default::default()
+        }
+    }
+}
+
+/// The name of a card type, as used in the model's response and in session
+/// files.
+pub(crate) fn card_type_name(card_type: CardType) -> &'static str {
+    match card_type {
+        CardType::Basic => "basic",
+        CardType::BasicReversed => "basic_reversed",
+        CardType::Cloze => "cloze",
+    }
+}
+
+/// The card type with the provided [card_type_name], if any.
+pub(crate) fn card_type_from_name(name: &str) -> Option<CardType> {
+    match name {
+        "basic" => Some(CardType::Basic),
+        "basic_reversed" => Some(CardType::BasicReversed),
+        "cloze" => Some(CardType::Cloze),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub(crate) fn approved_card(
+        id: &str,
+        card_type: CardType,
+        front: &str,
+        back: &str,
+    ) -> GeneratedCard {
+        GeneratedCard {
+            id: id.to_string(),
+            card_type: card_type.into(),
+            front: front.to_string(),
+            back: back.to_string(),
+            suggested_tags: vec![],
+            status: CardStatus::Approved.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn card_type_names_round_trip() {
+        for card_type in [CardType::Basic, CardType::BasicReversed, CardType::Cloze] {
+            assert_eq!(
+                card_type_from_name(card_type_name(card_type)),
+                Some(card_type)
+            );
+        }
+        assert_eq!(card_type_from_name("other"), None);
+    }
+}
@@ -0,0 +1,475 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Parsing of the JSON returned by the model.
+//!
+//! The expected format is `{"cards": [{"type", "front", "back",
+//! "suggested_tags"}]}`, as requested by the prompt in
+//! pylib/anki/ai_flashcards/openai_client.py.
+
+use std::collections::HashSet;
+
+use anki_proto::ai_flashcards::CardStatus;
+use anki_proto::ai_flashcards::CardType;
+use serde::Deserialize;
+
+use super::card_type_from_name;
+use super::AIGeneratedCard;
+use super::AiFlashcardsError;
+use crate::prelude::*;
+
+/// Responses larger than this aren't searched for embedded JSON.
+const MAX_FALLBACK_INPUT_LEN: usize = 4 * 1024 * 1024;
+/// Embedded JSON nested deeper than this is rejected; serde_json would refuse
+/// it anyway.
+const MAX_FALLBACK_DEPTH: usize = 128;
+
+#[derive(Debug, Deserialize)]
+pub(super) struct RawResponse {
+    pub cards: Vec<RawCard>,
+}
+
+impl RawResponse {
+    fn into_cards(self) -> Vec<AIGeneratedCard> {
+        self.cards.into_iter().map(Into::into).collect()
+    }
+}
+
+/// A card as provided by the model, before any normalization.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RawCard {
+    #[serde(rename = "type")]
+    pub card_type: String,
+    pub front: String,
+    pub back: String,
+    pub suggested_tags: Vec<String>,
+    pub confidence: Option<f32>,
+    pub reference: String,
+    /// The notetype the card should be imported into, if not the default for
+    /// its type
+    pub notetype: Option<String>,
+    pub notes: Option<String>,
+    pub examples: Vec<String>,
+}
+
+impl From<RawCard> for AIGeneratedCard {
+    /// The id is left empty; it is assigned when the card is added to a
+    /// session.
+    fn from(card: RawCard) -> Self {
+        card.into_card(None)
+    }
+}
+
+impl RawCard {
+    /// Convert the card, removing duplicate tags (ignoring case, and keeping
+    /// the first spelling) and keeping at most `max_tags` tags if provided.
+    pub fn into_card(self, max_tags: Option<usize>) -> AIGeneratedCard {
+        let mut suggested_tags: Vec<String> = vec![];
+        let mut seen = HashSet::new();
+        for tag in self.suggested_tags {
+            let tag = tag.trim();
+            if !tag.is_empty() && seen.insert(tag.to_lowercase()) {
+                suggested_tags.push(tag.to_string());
+            }
+        }
+        if let Some(max_tags) = max_tags {
+            suggested_tags.truncate(max_tags);
+        }
+        AIGeneratedCard {
+            id: String::new(),
+            // Default to basic if type is invalid, as the Python layer does
+            card_type: card_type_from_name(&self.card_type).unwrap_or(CardType::Basic),
+            front: self.front,
+            back: self.back,
+            suggested_tags,
+            status: CardStatus::Pending,
+            confidence: self.confidence,
+            reference: self.reference,
+            notetype: self.notetype.filter(|name| !name.trim().is_empty()),
+            notes: self.notes.filter(|notes| !notes.trim().is_empty()),
+            examples: self
+                .examples
+                .into_iter()
+                .map(|example| example.trim().to_string())
+                .filter(|example| !example.is_empty())
+                .collect(),
+            // set when the card is added to a session
+            created_timestamp: 0,
+        }
+    }
+}
+
+/// Parse the model's response into cards, in the order they were provided.
+pub fn parse_openai_response(json: &str) -> Result<Vec<AIGeneratedCard>> {
+    Ok(parse_raw_response(json)?.into_cards())
+}
+
+/// Like [parse_openai_response], for callers that have already parsed the
+/// response into a JSON tree. The same formats are accepted: the cards
+/// object, a chat-completion response, or a string containing either.
+pub fn parse_openai_value(value: &serde_json::Value) -> Result<Vec<AIGeneratedCard>> {
+    let response = if let Some(text) = value.as_str() {
+        parse_raw_response(text)?
+    } else if let Some(content) = value
+        .pointer("/choices/0/message/content")
+        .and_then(|content| content.as_str())
+    {
+        parse_raw_response(content)?
+    } else {
+        RawResponse::deserialize(value).map_err(parse_failed)?
+    };
+    Ok(response.into_cards())
+}
+
+/// Orderings that can be applied to parsed cards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    /// Keep the order the model provided.
+    #[default]
+    Original,
+    /// Basic, then reversed, then cloze; cards of the same type are ordered by
+    /// their front.
+    CardType,
+    /// Case-insensitive alphabetical order of the front.
+    Front,
+}
+
+/// Sort cards in place. The sort is stable, so cards that compare equal keep
+/// their original relative order.
+pub fn sort_cards(cards: &mut [AIGeneratedCard], key: SortKey) {
+    match key {
+        SortKey::Original => {}
+        SortKey::CardType => {
+            cards.sort_by_cached_key(|card| (card.card_type, card.front.to_lowercase()))
+        }
+        SortKey::Front => cards.sort_by_cached_key(|card| card.front.to_lowercase()),
+    }
+}
+
+pub(super) fn parse_raw_response(json: &str) -> Result<RawResponse> {
+    match parse_response_text(json) {
+        Ok(response) => Ok(response),
+        // Some pipelines deliver the JSON as an escaped string. It is only
+        // unescaped once, so repeatedly-encoded input can't loop.
+        Err(err) => match serde_json::from_str::<String>(json.trim()) {
+            Ok(unescaped) => parse_response_text(&unescaped),
+            Err(_) => Err(err),
+        },
+    }
+}
+
+fn parse_response_text(json: &str) -> Result<RawResponse> {
+    match serde_json::from_str(json) {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            if let Some(content) = chat_completion_content(json) {
+                return parse_raw_response(&content);
+            }
+            if let Some(response) = parse_concatenated_responses(json) {
+                return Ok(response);
+            }
+            // The model sometimes surrounds the JSON with prose or a code
+            // fence, so fall back to the first complete object.
+            match extract_json_object(json)? {
+                Some(object) => serde_json::from_str(object).map_err(parse_failed),
+                None => Err(parse_failed(err)),
+            }
+        }
+    }
+}
+
+fn parse_failed(err: serde_json::Error) -> AnkiError {
+    AiFlashcardsError::ParseFailed {
+        info: err.to_string(),
+    }
+    .into()
+}
+
+/// Some streaming responses emit several `{"cards": [...]}` objects
+/// back-to-back. If the input consists only of such objects, return a single
+/// response with all of their cards, in order.
+fn parse_concatenated_responses(json: &str) -> Option<RawResponse> {
+    let mut cards = vec![];
+    let mut count = 0;
+    for response in serde_json::Deserializer::from_str(json).into_iter::<RawResponse>() {
+        cards.extend(response.ok()?.cards);
+        count += 1;
+    }
+    (count > 1).then_some(RawResponse { cards })
+}
+
+/// Find the first balanced `{...}` span in the text, in a single pass.
+/// Braces inside strings are ignored. Oversized or deeply nested input is
+/// rejected, so that hostile responses can't cause excessive work.
+fn extract_json_object(text: &str) -> Result<Option<&str>> {
+    if text.len() > MAX_FALLBACK_INPUT_LEN {
+        return Err(AiFlashcardsError::ParseFailed {
+            info: "response is too large to search for JSON".into(),
+        }
+        .into());
+    }
+    let Some(start) = text.find('{') else {
+        return Ok(None);
+    };
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (idx, byte) in text.bytes().enumerate().skip(start) {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > MAX_FALLBACK_DEPTH {
+                    return Err(AiFlashcardsError::ParseFailed {
+                        info: "response is nested too deeply".into(),
+                    }
+                    .into());
+                }
+            }
+            b'}' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(&text[start..=idx]));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// If the input is a complete chat-completion response, return the content of
+/// the first choice's message, which holds the cards.
+fn chat_completion_content(json: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(json).ok()?;
+    value
+        .pointer("/choices/0/message/content")?
+        .as_str()
+        .map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_cards_in_order() {
+        let json = r#"{"cards": [
+            {"type": "basic", "front": "Q", "back": "A", "suggested_tags": ["t"]},
+            {"type": "cloze", "front": "{{c1::A}}"},
+            {"type": "unknown", "front": "Q2", "back": "A2"}
+        ]}"#;
+        let cards = parse_openai_response(json).unwrap();
+        assert_eq!(cards.len(), 3);
+        assert_eq!(cards[0].card_type, CardType::Basic);
+        assert_eq!(cards[0].suggested_tags, ["t"]);
+        assert_eq!(cards[1].card_type, CardType::Cloze);
+        assert_eq!(cards[1].back, "");
+        assert_eq!(cards[2].card_type, CardType::Basic);
+    }
+
+    #[test]
+    fn json_is_extracted_from_surrounding_text() {
+        let json = "Here you go:\n```json\n{\"cards\": [{\"type\": \"basic\", \"front\": \"Q\", \
+                    \"back\": \"A\"}]}\n```";
+        assert_eq!(parse_openai_response(json).unwrap().len(), 1);
+        assert!(parse_openai_response("no json here").is_err());
+    }
+
+    #[test]
+    fn cards_can_be_sorted_by_type() {
+        let json = r#"{"cards": [
+            {"type": "cloze", "front": "{{c1::b}}"},
+            {"type": "basic", "front": "b", "back": "x"},
+            {"type": "basic_reversed", "front": "a", "back": "x"},
+            {"type": "cloze", "front": "{{c1::a}}"},
+            {"type": "basic", "front": "a", "back": "x"}
+        ]}"#;
+        let original = parse_openai_response(json).unwrap();
+        let mut cards = original.clone();
+        sort_cards(&mut cards, SortKey::Original);
+        assert_eq!(cards, original);
+
+        sort_cards(&mut cards, SortKey::CardType);
+        let sorted: Vec<_> = cards
+            .iter()
+            .map(|c| (c.card_type, c.front.as_str()))
+            .collect();
+        assert_eq!(
+            sorted,
+            [
+                (CardType::Basic, "a"),
+                (CardType::Basic, "b"),
+                (CardType::BasicReversed, "a"),
+                (CardType::Cloze, "{{c1::a}}"),
+                (CardType::Cloze, "{{c1::b}}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn cards_are_extracted_from_chat_completion_envelope() {
+        let content = r#"{"cards": [{"type": "basic", "front": "Q", "back": "A"}]}"#;
+        let envelope = serde_json::json!({
+            "id": "chatcmpl-123",
+            "object": "chat.completion",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": content},
+                "finish_reason": "stop"
+            }],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 20}
+        })
+        .to_string();
+        let cards = parse_openai_response(&envelope).unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].front, "Q");
+        assert_eq!(cards[0].back, "A");
+    }
+
+    #[test]
+    fn double_encoded_json_is_unescaped_once() {
+        let json = r#"{"cards": [{"type": "basic", "front": "Q", "back": "A"}]}"#;
+        let encoded = serde_json::to_string(json).unwrap();
+        let cards = parse_openai_response(&encoded).unwrap();
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].front, "Q");
+
+        let twice_encoded = serde_json::to_string(&encoded).unwrap();
+        assert!(parse_openai_response(&twice_encoded).is_err());
+    }
+
+    #[test]
+    fn unreadable_responses_report_parse_failure() {
+        for json in [
+            "no cards here",
+            r#"{"cards": "none"}"#,
+            r#"Sure: {"cards": 5}"#,
+        ] {
+            let err = parse_openai_response(json).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    AnkiError::AiFlashcardsError {
+                        source: AiFlashcardsError::ParseFailed { .. }
+                    }
+                ),
+                "{json}: {err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn pathological_input_is_rejected() {
+        let deep = format!("prefix {}", "{\"a\": [".repeat(100_000));
+        let err = parse_openai_response(&deep).unwrap_err();
+        assert!(matches!(
+            err,
+            AnkiError::AiFlashcardsError {
+                source: AiFlashcardsError::ParseFailed { .. }
+            }
+        ));
+
+        let huge = format!("prefix {{{}}}", " ".repeat(MAX_FALLBACK_INPUT_LEN));
+        let err = parse_openai_response(&huge).unwrap_err();
+        assert!(matches!(
+            err,
+            AnkiError::AiFlashcardsError {
+                source: AiFlashcardsError::ParseFailed { .. }
+            }
+        ));
+
+        // braces in strings don't confuse the scan
+        let json =
+            r#"Sure! {"cards": [{"type": "basic", "front": "a } b", "back": "{"}]} Thanks {}"#;
+        assert_eq!(parse_openai_response(json).unwrap()[0].front, "a } b");
+    }
+
+    #[test]
+    fn reference_is_parsed_separately_from_back() {
+        let json = r#"{"cards": [
+            {"type": "basic", "front": "Q", "back": "A", "reference": "Chapter 2"},
+            {"type": "basic", "front": "Q2", "back": "A2"}
+        ]}"#;
+        let cards = parse_openai_response(json).unwrap();
+        assert_eq!(cards[0].back, "A");
+        assert_eq!(cards[0].reference, "Chapter 2");
+        assert_eq!(cards[1].reference, "");
+    }
+
+    #[test]
+    fn notetype_can_be_specified_per_card() {
+        let json = r#"{"cards": [
+            {"type": "basic", "front": "Q", "back": "A", "notetype": "Vocabulary"},
+            {"type": "basic", "front": "Q2", "back": "A2", "notetype": " "},
+            {"type": "cloze", "front": "{{c1::a}}", "back": ""}
+        ]}"#;
+        let cards = parse_openai_response(json).unwrap();
+        assert_eq!(cards[0].notetype.as_deref(), Some("Vocabulary"));
+        assert_eq!(cards[1].notetype, None);
+        assert_eq!(cards[2].notetype, None);
+    }
+
+    #[test]
+    fn duplicate_tags_are_removed() {
+        let raw = RawCard {
+            suggested_tags: [
+                "Biology", "biology", " cells ", "BIOLOGY", "", "Cells", "dna",
+            ]
+            .map(ToString::to_string)
+            .to_vec(),
+            ..Default::default()
+        };
+        let card = AIGeneratedCard::from(raw.clone());
+        assert_eq!(card.suggested_tags, ["Biology", "cells", "dna"]);
+        assert_eq!(raw.into_card(Some(2)).suggested_tags, ["Biology", "cells"]);
+    }
+
+    #[test]
+    fn concatenated_responses_are_combined() {
+        let json = r#"{"cards": [{"front": "Q1", "back": "A1"}]}
+            {"cards": [{"front": "Q2", "back": "A2"}, {"front": "Q3", "back": "A3"}]}"#;
+        let cards = parse_openai_response(json).unwrap();
+        let fronts: Vec<_> = cards.iter().map(|c| c.front.as_str()).collect();
+        assert_eq!(fronts, ["Q1", "Q2", "Q3"]);
+    }
+
+    #[test]
+    fn examples_are_parsed() {
+        let json = r#"{"cards": [
+            {"front": "la casa", "back": "the house", "examples": ["Mi casa es grande.", " "]},
+            {"front": "el perro", "back": "the dog"}
+        ]}"#;
+        let cards = parse_openai_response(json).unwrap();
+        assert_eq!(cards[0].examples, ["Mi casa es grande."]);
+        assert!(cards[1].examples.is_empty());
+    }
+
+    #[test]
+    fn pre_parsed_values_are_accepted() {
+        let value = serde_json::json!({"cards": [
+            {"type": "cloze", "front": "{{c1::Paris}}", "suggested_tags": ["geo", "Geo"]},
+        ]});
+        let cards = parse_openai_value(&value).unwrap();
+        assert_eq!(cards, parse_openai_response(&value.to_string()).unwrap());
+        assert_eq!(cards[0].card_type, CardType::Cloze);
+        assert_eq!(cards[0].suggested_tags, ["geo"]);
+
+        let completion = serde_json::json!({"choices": [
+            {"message": {"content": value.to_string()}}
+        ]});
+        assert_eq!(parse_openai_value(&completion).unwrap(), cards);
+
+        assert!(parse_openai_value(&serde_json::json!({"cards": 1})).is_err());
+    }
+}
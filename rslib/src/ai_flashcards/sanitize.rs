@@ -0,0 +1,327 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Cleanup of AI-generated content so it satisfies Anki's constraints.
+
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::text::decode_entities;
+use crate::text::sanitize_html;
+use crate::text::strip_html_preserving_entities;
+
+/// Matches the start of an HTML tag, comment or processing instruction.
+static TAG_START: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<([a-zA-Z/!?])").unwrap());
+
+/// Matches a script or style element and its content, up to the end of the
+/// text if it isn't closed.
+static SCRIPT_OR_STYLE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<script\b.*?(?:</script\s*>|$)|<style\b.*?(?:</style\s*>|$)").unwrap()
+});
+
+/// Matches an HTML tag, including any quoted attribute values, which may
+/// contain `>`. Tags and values that aren't closed extend to the end of the
+/// text.
+static TAG_SPAN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"<[a-zA-Z/!?][^"'>]*(?:(?:"[^"]*(?:"|$)|'[^']*(?:'|$))[^"'>]*)*(?:>|$)"#).unwrap()
+});
+
+/// Matches an event handler attribute such as `onclick="..."`. Browsers also
+/// accept a slash before an attribute, as in `<svg/onload=...>`. Only applied
+/// within [TAG_SPAN], so text like `go online=true` is left alone.
+static EVENT_HANDLER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)[\s/]+on[a-z]+\s*=\s*(?:"[^"]*"|'[^']*'|[^\s>]+)"#).unwrap()
+});
+
+/// Matches a paragraph or div with no visible content.
+static EMPTY_BLOCK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)<p\b[^>]*>(?:\s|&nbsp;|<br\s*/?>)*</p\s*>|<div\b[^>]*>(?:\s|&nbsp;|<br\s*/?>)*</div\s*>",
+    )
+    .unwrap()
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TagSanitizeConfig {
+    /// Tags longer than this many characters are truncated, as very long tags
+    /// make the browser's sidebar unusable.
+    pub max_len: usize,
+    /// Non-alphanumeric characters that are kept. Others are removed.
+    /// `:` is needed for hierarchical tags.
+    pub allowed_punctuation: String,
+}
+
+impl Default for TagSanitizeConfig {
+    fn default() -> Self {
+        TagSanitizeConfig {
+            max_len: 60,
+            allowed_punctuation: "_-:".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SanitizedTag {
+    /// The cleaned tag, or `None` if nothing usable remained.
+    pub tag: Option<String>,
+    /// Changes the user may want to know about. Routine cleanup, such as
+    /// replacing spaces, is not reported.
+    pub warnings: Vec<String>,
+}
+
+/// Convert a tag suggested by the model into a valid Anki tag. Spaces become
+/// underscores, unsupported characters are removed, empty hierarchy
+/// components are dropped, and overlong tags are truncated.
+pub fn sanitize_tag(tag: &str, config: &TagSanitizeConfig) -> SanitizedTag {
+    let mut warnings = vec![];
+    let cleaned: String = tag
+        .trim()
+        .chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .filter(|c| c.is_alphanumeric() || config.allowed_punctuation.contains(*c))
+        .collect();
+
+    let non_empty = tag_components(&cleaned);
+    if !non_empty.is_empty() && non_empty.len() != cleaned.split("::").count() {
+        warnings.push(format!(
+            "Tag '{tag}' contained empty hierarchy components, which were removed"
+        ));
+    }
+    let mut cleaned = non_empty.join("::");
+
+    if cleaned.chars().count() > config.max_len {
+        cleaned = cleaned.chars().take(config.max_len).collect();
+        // don't leave a dangling separator behind
+        cleaned.truncate(cleaned.trim_end_matches(':').len());
+        warnings.push(format!(
+            "Tag '{tag}' was longer than {} characters and was truncated",
+            config.max_len
+        ));
+    }
+
+    if cleaned.is_empty() {
+        warnings.push(format!(
+            "Tag '{tag}' has no usable characters and was dropped"
+        ));
+        SanitizedTag {
+            tag: None,
+            warnings,
+        }
+    } else {
+        SanitizedTag {
+            tag: Some(cleaned),
+            warnings,
+        }
+    }
+}
+
+/// Split a tag into its hierarchy components, for display as a tree. Empty
+/// components, as left by stray separators in `a::::b` or `::a`, are skipped,
+/// so a tag without any usable component produces an empty list.
+pub fn tag_components(tag: &str) -> Vec<&str> {
+    tag.split("::").filter(|comp| !comp.is_empty()).collect()
+}
+
+/// A field as plain text, for previews and comparisons. Tags are removed,
+/// named and numeric entities are decoded once (so `&amp;lt;` becomes `&lt;`),
+/// and whitespace, including non-breaking spaces, is collapsed.
+pub fn plain_text(html: &str) -> String {
+    decode_entities(&strip_html_preserving_entities(html))
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Remove scripts, event handlers and other unsafe HTML from a field.
+pub fn sanitize_card_html(html: &str) -> String {
+    sanitize_html(html)
+}
+
+/// Remove paragraphs and divs with no visible content, which break the
+/// rendering of cloze fronts. Cloze markers are left untouched, and blocks
+/// that only become empty once their empty children are removed are removed
+/// too.
+pub fn strip_empty_blocks(html: &str) -> Cow<'_, str> {
+    let strip = |text: &str| match EMPTY_BLOCK.replace_all(text, "") {
+        Cow::Owned(stripped) => Some(stripped),
+        Cow::Borrowed(_) => None,
+    };
+    let mut text = Cow::Borrowed(html);
+    while let Some(stripped) = strip(&text) {
+        text = Cow::Owned(stripped);
+    }
+    text
+}
+
+/// A faster alternative to [sanitize_card_html] for very large fields, which
+/// only removes script and style elements and event handler attributes.
+///
+/// This is much weaker: other unsafe content, such as `javascript:` links or
+/// iframes, is kept, and malformed HTML is not repaired, so it should only be
+/// used for content from a trusted pipeline where speed matters.
+pub fn sanitize_card_html_fast(html: &str) -> String {
+    let html = SCRIPT_OR_STYLE.replace_all(html, "");
+    TAG_SPAN
+        .replace_all(&html, |caps: &regex::Captures| {
+            EVENT_HANDLER.replace_all(&caps[0], "").into_owned()
+        })
+        .into_owned()
+}
+
+/// Make a Markdown field safe without converting it, by escaping anything
+/// that could start an HTML tag. Markdown syntax, and `<` not followed by a
+/// tag name (as in `a < b`), are left alone.
+pub fn sanitize_card_markdown(text: &str) -> Cow<'_, str> {
+    TAG_START.replace_all(text, "&lt;$1")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sanitized(tag: &str) -> SanitizedTag {
+        sanitize_tag(tag, &TagSanitizeConfig::default())
+    }
+
+    #[test]
+    fn routine_cleanup_is_silent() {
+        let result = sanitized(" cell biology! ");
+        assert_eq!(result.tag.as_deref(), Some("cell_biology"));
+        assert!(result.warnings.is_empty());
+        assert_eq!(
+            sanitized("Biologie::Zellen").tag.as_deref(),
+            Some("Biologie::Zellen")
+        );
+    }
+
+    #[test]
+    fn overlong_tags_are_truncated() {
+        let config = TagSanitizeConfig {
+            max_len: 10,
+            ..Default::default()
+        };
+        let result = sanitize_tag(&"a".repeat(20), &config);
+        assert_eq!(result.tag, Some("a".repeat(10)));
+        assert_eq!(result.warnings.len(), 1);
+
+        // truncation never leaves a trailing separator
+        let result = sanitize_tag("abcdefgh::ijk", &config);
+        assert_eq!(result.tag.as_deref(), Some("abcdefgh"));
+    }
+
+    #[test]
+    fn empty_hierarchy_components_are_removed() {
+        let result = sanitized("::biology::::cells::");
+        assert_eq!(result.tag.as_deref(), Some("biology::cells"));
+        assert_eq!(result.warnings.len(), 1);
+
+        let result = sanitized("::!!");
+        assert_eq!(result.tag, None);
+        assert_eq!(result.warnings.len(), 1);
+    }
+
+    #[test]
+    fn allowed_punctuation_is_configurable() {
+        assert_eq!(sanitized("C++/Rust v1.2").tag.as_deref(), Some("CRust_v12"));
+        let config = TagSanitizeConfig {
+            allowed_punctuation: "_-:/.".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            sanitize_tag("C++/Rust v1.2", &config).tag.as_deref(),
+            Some("C/Rust_v1.2")
+        );
+    }
+
+    #[test]
+    fn unsafe_html_is_removed() {
+        assert_eq!(
+            sanitize_card_html("<b onclick=\"x()\">bold</b><script>x()</script>"),
+            "<b>bold</b>"
+        );
+        assert_eq!(sanitize_card_html("{{c1::Paris}}"), "{{c1::Paris}}");
+    }
+
+    #[test]
+    fn markdown_is_kept_but_html_escaped() {
+        assert_eq!(
+            sanitize_card_markdown("**bold** and `code` where a < b"),
+            "**bold** and `code` where a < b"
+        );
+        assert_eq!(
+            sanitize_card_markdown("<script>x()</script>"),
+            "&lt;script>x()&lt;/script>"
+        );
+    }
+
+    #[test]
+    fn fast_sanitizing_matches_on_simple_input() {
+        let html = "<b onclick=\"x()\">bold</b><script>x()</script><style>b {}</style>";
+        assert_eq!(sanitize_card_html_fast(html), sanitize_card_html(html));
+        assert_eq!(sanitize_card_html_fast(html), "<b>bold</b>");
+        // unclosed scripts are removed to the end
+        assert_eq!(sanitize_card_html_fast("a<script>x()"), "a");
+    }
+
+    #[test]
+    fn slash_separated_handlers_are_removed() {
+        assert_eq!(sanitize_card_html_fast("<svg/onload=alert(1)>"), "<svg>");
+        assert_eq!(
+            sanitize_card_html_fast("<img src=x/onerror=alert(1)>"),
+            "<img src=x>"
+        );
+    }
+
+    #[test]
+    fn handler_like_text_outside_tags_is_kept() {
+        let text = "go online=true, or see a / onset=early";
+        assert_eq!(sanitize_card_html_fast(text), text);
+        assert_eq!(
+            sanitize_card_html_fast("<b>go online=true</b><i onclick=x>a</i>"),
+            "<b>go online=true</b><i>a</i>"
+        );
+        // a quoted > doesn't end the tag
+        assert_eq!(
+            sanitize_card_html_fast(r#"<img alt="a>b" onerror=alert(1)>"#),
+            r#"<img alt="a>b">"#
+        );
+    }
+
+    #[test]
+    fn tags_are_split_into_components() {
+        assert_eq!(tag_components("a::b::c"), ["a", "b", "c"]);
+        assert_eq!(tag_components("a"), ["a"]);
+        assert_eq!(tag_components("::a::::b::"), ["a", "b"]);
+        assert!(tag_components("").is_empty());
+        assert!(tag_components("::::").is_empty());
+    }
+
+    #[test]
+    fn plain_text_decodes_entities_once() {
+        assert_eq!(plain_text("<b>salt &amp; pepper</b>"), "salt & pepper");
+        assert_eq!(plain_text("it&#39;s"), "it's");
+        assert_eq!(plain_text("a&nbsp;&nbsp;b <br> c"), "a b c");
+        assert_eq!(plain_text("&amp;lt;b&amp;gt;"), "&lt;b&gt;");
+    }
+
+    #[test]
+    fn empty_blocks_are_stripped() {
+        assert_eq!(
+            strip_empty_blocks("<p></p><p>{{c1::Paris}} is in France</p><p> &nbsp;<br></p>"),
+            "<p>{{c1::Paris}} is in France</p>"
+        );
+        assert_eq!(
+            strip_empty_blocks("<div><p></p></div>{{c1::Paris}}"),
+            "{{c1::Paris}}"
+        );
+        assert!(matches!(
+            strip_empty_blocks("<p>text</p>"),
+            Cow::Borrowed(_)
+        ));
+    }
+}
@@ -11,11 +11,11 @@
 //!
 //! This Rust service handles:
 //! - Card import (using existing Anki import infrastructure)
-//! - Session persistence (file-based JSON storage)
+//! - Session persistence (file-based JSON storage, see session.rs)
 //!
 //! The Python layer is called directly from the Qt frontend for AI operations.
 
-use std::fs;
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use anki_proto::ai_flashcards::CardStatus;
@@ -23,112 +23,562 @@ use anki_proto::ai_flashcards::CardType;
 use anki_proto::ai_flashcards::GeneratedCard;
 use anki_proto::ai_flashcards::ImportApprovedCardsRequest;
 use anki_proto::ai_flashcards::ImportApprovedCardsResponse;
+use anki_proto::ai_flashcards::LoadSessionRequest;
 use anki_proto::ai_flashcards::LoadSessionResponse;
 use anki_proto::ai_flashcards::SaveSessionRequest;
+use anki_proto::ai_flashcards::SaveSessionResponse;
+use anki_proto::scheduler::bury_or_suspend_cards_request::Mode as BuryOrSuspendMode;
+use itertools::Itertools;
+use prost::Message;
 use serde::Deserialize;
 use serde::Serialize;
 use snafu::FromString;
+use tracing::debug;
+use tracing::trace;
+use tracing::warn;
 
+use super::convert::deck_name_from_source;
+use super::convert::default_notetype_name;
+use super::convert::expand_reversed;
+use super::convert::fields_for_notetype;
+use super::convert::missing_cloze_fields;
+use super::convert::split_cloze_card;
+use super::convert::target_field_ords;
+use super::convert::to_foreign_note;
+use super::convert::ConvertConfig;
+use super::convert::GuidMode;
+use super::convert::Provenance;
+use super::convert::AI_GENERATED_TAG;
+use super::parse::sort_cards;
+use super::parse::SortKey;
+use super::sanitize::sanitize_card_html;
+use super::validate::cloze_indices;
+use super::validate::validate_card;
+use super::validate::QualityGate;
+use super::validate::ValidationConfig;
+use super::AIGeneratedCard;
+use super::AiFlashcardsError;
 use crate::error;
 use crate::import_export::text::ForeignNote;
 use crate::import_export::text::NameOrId;
+use crate::notetype::all_stock_notetypes;
 use crate::prelude::*;
+use crate::scheduler::parse_due_date_str;
+use crate::search::SearchNode;
+use crate::text::replace_media_refs;
+use crate::text::strip_html_preserving_media_filenames;
 
-/// Session file format version for compatibility checking
-const SESSION_VERSION: u32 = 1;
+/// Config key holding the notes created by each session's imports, so they
+/// can be removed again.
+const SESSION_IMPORTS_KEY: &str = "aiFlashcardsSessionImports";
 
-/// Maximum session age in seconds (7 days)
-const SESSION_MAX_AGE_SECS: i64 = 7 * 24 * 60 * 60;
+/// Config key holding the responses of recent imports that had an
+/// idempotency key, oldest first.
+const COMPLETED_IMPORTS_KEY: &str = "aiFlashcardsCompletedImports";
 
-/// Session file name
-const SESSION_FILENAME: &str = "ai_flashcards_session.json";
+/// How many keyed imports are remembered.
+const MAX_COMPLETED_IMPORTS: usize = 20;
 
-/// Serializable session format for JSON persistence
+/// Options controlling how AI-generated cards are imported.
+#[derive(Debug, Clone, Default)]
+pub struct AIImportOptions {
+    /// Tags to apply to every imported note.
+    pub additional_tags: Vec<String>,
+    /// If set, the target deck must be one of these decks.
+    pub allowed_decks: Option<Vec<NameOrId>>,
+    pub provenance: Option<Provenance>,
+    /// If set, the created notes are recorded so the import can be reverted
+    /// with [Collection::delete_session_import].
+    pub session_id: Option<String>,
+    /// Import even if the stored session with `session_id` was already
+    /// imported. Otherwise, that fails with
+    /// [AiFlashcardsError::SessionFinalized].
+    pub allow_finalized_session: bool,
+    /// If set, approved cards are imported in chunks of this size, each in
+    /// its own transaction. A failing chunk stops the import, but earlier
+    /// chunks are kept.
+    pub chunk_size: Option<usize>,
+    /// If set, approved cards that don't pass the gate are skipped.
+    pub quality_gate: Option<QualityGate>,
+    /// Media files referenced by the cards, keyed by the name they are
+    /// referenced with. They are copied into the media folder, and references
+    /// are updated if a file had to be renamed.
+    pub media: HashMap<String, MediaSource>,
+    /// If set, and a recent import used the same key, nothing is imported
+    /// and that import's response is returned instead, so a retried request
+    /// can't import the cards twice.
+    pub idempotency_key: Option<String>,
+    /// What to do with cards whose first field matches an existing note of
+    /// the same notetype in the target deck.
+    pub on_duplicate: OnDuplicate,
+    pub guid_mode: GuidMode,
+    /// If a required notetype is missing and it is one of Anki's stock
+    /// notetypes, add it instead of failing. Other notetypes are still
+    /// reported as missing.
+    pub create_missing_notetypes: bool,
+    /// What to do with BasicReversed cards if the reversed notetype is
+    /// missing.
+    pub reversed_fallback: ReversedFallback,
+    /// Written to the "Add Reverse" field of reversed cards, if their
+    /// notetype has one.
+    pub add_reverse_marker: Option<String>,
+    /// If set, cards are imported into a deck named after this source, as
+    /// given by [deck_name_from_source], instead of the target deck. The
+    /// deck is created if it doesn't exist.
+    pub create_deck_from_source: Option<String>,
+    /// If set, the most distinct cloze deletions a card may have. Cards with
+    /// more are handled according to `cloze_overflow`.
+    pub max_cloze_deletions: Option<usize>,
+    pub cloze_overflow: ClozeOverflow,
+    /// The cards of imported notes with any of these tags are suspended,
+    /// eg so drafts can be reviewed before they are studied. Tags are
+    /// matched ignoring case.
+    pub suspend_tags: Vec<String>,
+    /// If set, imported cards are scheduled as review cards with this
+    /// interval in days, and are due after it, as when using Set Due Date
+    /// with a "!" suffix. This suits material already learned elsewhere.
+    pub initial_interval: Option<u32>,
+    /// The order cards are imported in, which determines the order of the
+    /// created notes.
+    pub sort: SortKey,
+    /// HTML appended to the back of every imported note, such as an
+    /// attribution. It is sanitized once before the import.
+    pub back_footer_html: Option<String>,
+    /// If set, the cards of imported notes are given this flag (1-7), so
+    /// they stand out in the browser.
+    pub flag: Option<u8>,
+    /// Skip cards with a field that sanitization would leave empty, as it
+    /// only contains unsafe content, reporting each in the response's
+    /// errors. The other cards are still imported. Fields aren't sanitized
+    /// on import, so such cards are otherwise imported unchanged.
+    pub skip_emptied_cards: bool,
+    /// The rules cards are checked with for `skip_emptied_cards`, eg so
+    /// Markdown fields aren't treated as HTML.
+    pub validation: ValidationConfig,
+    /// Tag notes with the model and date from `provenance`; see
+    /// [ConvertConfig::provenance_tags].
+    pub provenance_tags: bool,
+    /// Applied to the cards' suggested tags; see [ConvertConfig::tag_map].
+    pub tag_map: HashMap<String, Option<String>>,
+    pub strict_tag_map: bool,
+    /// See [ConvertConfig::newlines_to_br].
+    pub newlines_to_br: bool,
+    /// See [ConvertConfig::collapse_whitespace].
+    pub collapse_whitespace: bool,
+    /// See [ConvertConfig::strip_surrounding_quotes].
+    pub strip_surrounding_quotes: bool,
+    /// See [ConvertConfig::trim_front].
+    pub trim_front: bool,
+    /// See [ConvertConfig::trim_back].
+    pub trim_back: bool,
+    /// See [ConvertConfig::auto_cloze_hint].
+    pub auto_cloze_hint: bool,
+}
+
+/// How cloze cards with more deletions than allowed are imported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClozeOverflow {
+    /// Import the card anyway, with a warning.
+    #[default]
+    Import,
+    /// Split the card with [split_cloze_card].
+    Split,
+    /// Don't import the card, reporting an error.
+    Reject,
+}
+
+impl From<anki_proto::ai_flashcards::ClozeOverflow> for ClozeOverflow {
+    fn from(value: anki_proto::ai_flashcards::ClozeOverflow) -> Self {
+        match value {
+            anki_proto::ai_flashcards::ClozeOverflow::Import => ClozeOverflow::Import,
+            anki_proto::ai_flashcards::ClozeOverflow::Split => ClozeOverflow::Split,
+            anki_proto::ai_flashcards::ClozeOverflow::Reject => ClozeOverflow::Reject,
+        }
+    }
+}
+
+impl From<anki_proto::ai_flashcards::CardSortKey> for SortKey {
+    fn from(value: anki_proto::ai_flashcards::CardSortKey) -> Self {
+        match value {
+            anki_proto::ai_flashcards::CardSortKey::Original => SortKey::Original,
+            anki_proto::ai_flashcards::CardSortKey::CardType => SortKey::CardType,
+            anki_proto::ai_flashcards::CardSortKey::Front => SortKey::Front,
+        }
+    }
+}
+
+/// How BasicReversed cards are imported when the collection has no
+/// "Basic (and reversed card)" notetype.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReversedFallback {
+    /// Fail the import, reporting the missing notetype.
+    #[default]
+    Error,
+    /// Add the stock reversed notetype.
+    Create,
+    /// Import each card as two Basic notes, one in each direction.
+    Degrade,
+}
+
+impl From<anki_proto::ai_flashcards::ReversedFallback> for ReversedFallback {
+    fn from(value: anki_proto::ai_flashcards::ReversedFallback) -> Self {
+        match value {
+            anki_proto::ai_flashcards::ReversedFallback::Error => ReversedFallback::Error,
+            anki_proto::ai_flashcards::ReversedFallback::Create => ReversedFallback::Create,
+            anki_proto::ai_flashcards::ReversedFallback::Degrade => ReversedFallback::Degrade,
+        }
+    }
+}
+
+/// How cards matching an existing note are handled on import.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnDuplicate {
+    /// Import the card as a new note anyway.
+    #[default]
+    Import,
+    /// Don't import the card.
+    Skip,
+    /// Don't import the card, but add its tags to the existing note.
+    MergeTags,
+}
+
+impl From<anki_proto::ai_flashcards::OnDuplicate> for OnDuplicate {
+    fn from(value: anki_proto::ai_flashcards::OnDuplicate) -> Self {
+        match value {
+            anki_proto::ai_flashcards::OnDuplicate::Import => OnDuplicate::Import,
+            anki_proto::ai_flashcards::OnDuplicate::Skip => OnDuplicate::Skip,
+            anki_proto::ai_flashcards::OnDuplicate::MergeTags => OnDuplicate::MergeTags,
+        }
+    }
+}
+
+/// The notes of a deck keyed by notetype and normalized first field, built
+/// once per import so duplicates can be found without querying the database
+/// for every card. First fields are compared as in Anki's duplicate check.
+#[derive(Debug, Default)]
+struct DuplicateIndex {
+    notes: HashMap<(NotetypeId, String), NoteId>,
+}
+
+impl DuplicateIndex {
+    fn fingerprint(first_field: &str) -> String {
+        strip_html_preserving_media_filenames(first_field).into_owned()
+    }
+
+    fn find(&self, note: &Note) -> Option<NoteId> {
+        self.notes
+            .get(&(note.notetype_id, Self::fingerprint(&note.fields()[0])))
+            .copied()
+    }
+
+    fn insert(&mut self, ntid: NotetypeId, first_field: &str, nid: NoteId) {
+        self.notes
+            .entry((ntid, Self::fingerprint(first_field)))
+            .or_insert(nid);
+    }
+}
+
+/// The content of a media file provided with an import.
+#[derive(Debug, Clone)]
+pub enum MediaSource {
+    Data(Vec<u8>),
+    Path(PathBuf),
+}
+
+impl MediaSource {
+    fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            MediaSource::Data(data) => Ok(data.clone()),
+            MediaSource::Path(path) => Ok(std::fs::read(path)?),
+        }
+    }
+}
+
+/// The response of an import made with an idempotency key.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct SessionFile {
-    version: u32,
-    created_timestamp: i64,
-    source_name: String,
-    source_text: String,
-    cards: Vec<SessionCard>,
+struct CompletedImport {
+    key: String,
+    /// The encoded [ImportApprovedCardsResponse].
+    response: Vec<u8>,
 }
 
-/// Card format for JSON persistence
+/// A note created by a session's import.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct SessionCard {
-    id: String,
-    card_type: String,
-    front: String,
-    back: String,
-    suggested_tags: Vec<String>,
-    status: String,
+struct ImportedNote {
+    id: NoteId,
+    /// Hash of the note's content when it was imported, to detect later edits.
+    checksum: String,
 }
 
-impl From<&GeneratedCard> for SessionCard {
-    fn from(card: &GeneratedCard) -> Self {
-        SessionCard {
-            id: card.id.clone(),
-            card_type: match card.card_type() {
-                CardType::Basic => "basic".to_string(),
-                CardType::BasicReversed => "basic_reversed".to_string(),
-                CardType::Cloze => "cloze".to_string(),
-            },
-            front: card.front.clone(),
-            back: card.back.clone(),
-            suggested_tags: card.suggested_tags.clone(),
-            status: match card.status() {
-                CardStatus::Pending => "pending".to_string(),
-                CardStatus::Approved => "approved".to_string(),
-                CardStatus::Rejected => "rejected".to_string(),
-            },
+impl ImportedNote {
+    fn new(note: &Note) -> Self {
+        ImportedNote {
+            id: note.id,
+            checksum: note_content_checksum(note),
         }
     }
 }
 
-impl From<SessionCard> for GeneratedCard {
-    fn from(card: SessionCard) -> Self {
-        GeneratedCard {
-            id: card.id,
-            card_type: match card.card_type.as_str() {
-                "basic" => CardType::Basic.into(),
-                "basic_reversed" => CardType::BasicReversed.into(),
-                "cloze" => CardType::Cloze.into(),
-                _ => CardType::Basic.into(),
-            },
-            front: card.front,
-            back: card.back,
-            suggested_tags: card.suggested_tags,
-            status: match card.status.as_str() {
-                "pending" => CardStatus::Pending.into(),
-                "approved" => CardStatus::Approved.into(),
-                "rejected" => CardStatus::Rejected.into(),
-                _ => CardStatus::Pending.into(),
-            },
+fn note_content_checksum(note: &Note) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(note.fields().join("\x1f").as_bytes());
+    hasher.update(b"\0");
+    hasher.update(note.tags.join(" ").as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Reorder cards before import. Sorting happens before any chunking, so the
+/// order holds across chunks.
+fn sort_for_import(cards: Vec<GeneratedCard>, key: SortKey) -> Vec<GeneratedCard> {
+    if key == SortKey::Original {
+        return cards;
+    }
+    let mut cards: Vec<AIGeneratedCard> = cards.into_iter().map(Into::into).collect();
+    sort_cards(&mut cards, key);
+    cards.into_iter().map(Into::into).collect()
+}
+
+/// Handle cloze cards with more than `max` distinct deletions as configured,
+/// reporting each one in `warnings`, or in `errors` if it is rejected.
+fn apply_cloze_limit(
+    cards: Vec<AIGeneratedCard>,
+    max: usize,
+    overflow: ClozeOverflow,
+    warnings: &mut Vec<String>,
+    errors: &mut Vec<String>,
+) -> Vec<AIGeneratedCard> {
+    let mut kept = Vec::with_capacity(cards.len());
+    for card in cards {
+        let count = cloze_indices(&card.front).len();
+        if card.card_type != CardType::Cloze || count <= max {
+            kept.push(card);
+            continue;
+        }
+        let message = format!(
+            "Card '{}' has {count} cloze deletions, more than the limit of {max}",
+            card.id
+        );
+        match overflow {
+            ClozeOverflow::Import => {
+                warnings.push(message);
+                kept.push(card);
+            }
+            ClozeOverflow::Split => {
+                let parts = split_cloze_card(card, max);
+                warnings.push(format!(
+                    "{message}; it was split into {} notes",
+                    parts.len()
+                ));
+                kept.extend(parts);
+            }
+            ClozeOverflow::Reject => errors.push(message),
         }
     }
+    kept
 }
 
-impl Collection {
-    /// Get the session file path for this collection
-    fn ai_session_path(&self) -> PathBuf {
-        self.col_path
-            .parent()
-            .map(|p| p.to_path_buf())
-            .unwrap_or_default()
-            .join(SESSION_FILENAME)
+impl From<&ImportApprovedCardsRequest> for AIImportOptions {
+    fn from(input: &ImportApprovedCardsRequest) -> Self {
+        AIImportOptions {
+            additional_tags: input.additional_tags.clone(),
+            allowed_decks: input.allowed_decks.as_ref().map(|allowed| {
+                allowed
+                    .deck_ids
+                    .iter()
+                    .map(|id| NameOrId::Id(*id))
+                    .chain(allowed.deck_names.iter().cloned().map(NameOrId::Name))
+                    .collect()
+            }),
+            provenance: (!input.model.is_empty() || !input.source_name.is_empty()).then(|| {
+                Provenance {
+                    model: input.model.clone(),
+                    source: input.source_name.clone(),
+                    timestamp: TimestampSecs::now().0,
+                }
+            }),
+            session_id: (!input.session_id.is_empty()).then(|| input.session_id.clone()),
+            allow_finalized_session: input.allow_finalized_session,
+            chunk_size: (input.chunk_size > 0).then_some(input.chunk_size as usize),
+            quality_gate: input.min_confidence.map(|min_confidence| QualityGate {
+                min_confidence,
+                ..Default::default()
+            }),
+            media: input
+                .media
+                .iter()
+                .map(|(name, data)| (name.clone(), MediaSource::Data(data.clone())))
+                .collect(),
+            idempotency_key: (!input.idempotency_key.is_empty())
+                .then(|| input.idempotency_key.clone()),
+            on_duplicate: input.on_duplicate().into(),
+            guid_mode: if input.content_guids {
+                GuidMode::Content
+            } else {
+                GuidMode::Random
+            },
+            create_missing_notetypes: input.create_missing_notetypes,
+            reversed_fallback: input.reversed_fallback().into(),
+            add_reverse_marker: (!input.add_reverse_marker.is_empty())
+                .then(|| input.add_reverse_marker.clone()),
+            create_deck_from_source: input
+                .create_deck_from_source
+                .then(|| input.source_name.clone()),
+            max_cloze_deletions: (input.max_cloze_deletions > 0)
+                .then_some(input.max_cloze_deletions as usize),
+            cloze_overflow: input.cloze_overflow().into(),
+            suspend_tags: input.suspend_tags.clone(),
+            initial_interval: (input.initial_interval > 0).then_some(input.initial_interval),
+            sort: input.sort().into(),
+            back_footer_html: (!input.back_footer_html.is_empty())
+                .then(|| input.back_footer_html.clone()),
+            flag: (input.flag > 0).then_some(input.flag as u8),
+            skip_emptied_cards: input.skip_emptied_cards,
+            provenance_tags: input.provenance_tags,
+            tag_map: input
+                .tag_map
+                .iter()
+                .map(|(tag, mapped)| (tag.clone(), (!mapped.is_empty()).then(|| mapped.clone())))
+                .collect(),
+            strict_tag_map: input.strict_tag_map,
+            newlines_to_br: input.newlines_to_br,
+            collapse_whitespace: input.collapse_whitespace,
+            strip_surrounding_quotes: input.strip_surrounding_quotes,
+            trim_front: input.trim_front,
+            trim_back: input.trim_back,
+            auto_cloze_hint: input.auto_cloze_hint,
+            validation: ValidationConfig::default(),
+        }
     }
+}
 
+impl Collection {
     /// Import approved AI-generated cards into the collection
     pub fn import_ai_cards(
         &mut self,
         cards: Vec<GeneratedCard>,
         target_deck_id: DeckId,
-        additional_tags: Vec<String>,
+        options: AIImportOptions,
     ) -> error::Result<ImportApprovedCardsResponse> {
-        self.transact(Op::Import, |col| {
-            col.import_ai_cards_inner(cards, target_deck_id, additional_tags)
-        })
-        .map(|output| output.output)
+        let cards = sort_for_import(cards, options.sort);
+        let key = options.idempotency_key.clone();
+        if let Some(key) = &key {
+            if let Some(response) = self.completed_ai_import(key)? {
+                debug!(key = %key, "import already completed");
+                return Ok(response);
+            }
+        }
+        let session_id = options.session_id.clone();
+        if let Some(session_id) = &session_id {
+            if !options.allow_finalized_session && self.ai_session_finalized(session_id)? {
+                return Err(AiFlashcardsError::SessionFinalized.into());
+            }
+        }
+        let response = if let Some(chunk_size) = options.chunk_size.filter(|size| *size > 0) {
+            let response =
+                self.import_ai_cards_chunked(cards, target_deck_id, options, chunk_size)?;
+            if let Some(key) = &key {
+                self.transact_no_undo(|col| col.record_completed_ai_import(key, &response))?;
+            }
+            response
+        } else {
+            self.transact(Op::Import, |col| {
+                let response = col.import_ai_cards_inner(cards, target_deck_id, options)?;
+                if let Some(key) = &key {
+                    col.record_completed_ai_import(key, &response)?;
+                }
+                Ok(response)
+            })?
+            .output
+        };
+        if let Some(session_id) = &session_id {
+            if response.imported_count > 0 {
+                self.finalize_ai_session(session_id)?;
+            }
+        }
+        Ok(response)
+    }
+
+    /// The response of a recent import made with the provided key, if any.
+    fn completed_ai_import(&self, key: &str) -> error::Result<Option<ImportApprovedCardsResponse>> {
+        let completed: Vec<CompletedImport> = self.get_config_default(COMPLETED_IMPORTS_KEY);
+        completed
+            .into_iter()
+            .find(|import| import.key == key)
+            .map(|import| {
+                Ok(ImportApprovedCardsResponse::decode(
+                    import.response.as_slice(),
+                )?)
+            })
+            .transpose()
+    }
+
+    /// Remember the response of a keyed import, forgetting the oldest one if
+    /// too many are stored.
+    fn record_completed_ai_import(
+        &mut self,
+        key: &str,
+        response: &ImportApprovedCardsResponse,
+    ) -> error::Result<()> {
+        let mut completed: Vec<CompletedImport> = self.get_config_default(COMPLETED_IMPORTS_KEY);
+        completed.retain(|import| import.key != key);
+        completed.push(CompletedImport {
+            key: key.to_string(),
+            response: response.encode_to_vec(),
+        });
+        let excess = completed.len().saturating_sub(MAX_COMPLETED_IMPORTS);
+        completed.drain(..excess);
+        self.set_config(COMPLETED_IMPORTS_KEY, &completed)?;
+        Ok(())
+    }
+
+    /// Import approved cards in separate transactions of `chunk_size` cards.
+    /// If a chunk fails, it is rolled back and reported, and the remaining
+    /// chunks are skipped. A deck outside the allowlist fails every chunk, so
+    /// it is returned as an error instead, as when importing without chunks.
+    fn import_ai_cards_chunked(
+        &mut self,
+        cards: Vec<GeneratedCard>,
+        target_deck_id: DeckId,
+        options: AIImportOptions,
+        chunk_size: usize,
+    ) -> error::Result<ImportApprovedCardsResponse> {
+        let approved: Vec<GeneratedCard> = cards
+            .into_iter()
+            .filter(|card| card.status() == CardStatus::Approved)
+            .collect();
+        let mut response = ImportApprovedCardsResponse::default();
+        for (idx, chunk) in approved.chunks(chunk_size).enumerate() {
+            match self.transact(Op::Import, |col| {
+                col.import_ai_cards_inner(chunk.to_vec(), target_deck_id, options.clone())
+            }) {
+                Ok(output) => {
+                    let output = output.output;
+                    response.changes = output.changes;
+                    response.imported_count += output.imported_count;
+                    response.duplicate_count += output.duplicate_count;
+                    response.generated_card_count += output.generated_card_count;
+                    response.gated_count += output.gated_count;
+                    response.errors.extend(output.errors);
+                    response.warnings.extend(output.warnings);
+                    response.deck_id = output.deck_id;
+                }
+                Err(
+                    e @ AnkiError::AiFlashcardsError {
+                        source: AiFlashcardsError::DeckNotAllowed { .. },
+                    },
+                ) => return Err(e),
+                Err(e) => {
+                    let first = &chunk[0].id;
+                    let last = &chunk[chunk.len() - 1].id;
+                    response.errors.push(format!(
+                        "Import stopped at chunk {} (cards '{first}' to '{last}'): {e}",
+                        idx + 1
+                    ));
+                    break;
+                }
+            }
+        }
+        Ok(response)
     }
 
     /// Inner implementation of card import, runs within a transaction
@@ -136,37 +586,242 @@ impl Collection {
         &mut self,
         cards: Vec<GeneratedCard>,
         target_deck_id: DeckId,
-        additional_tags: Vec<String>,
+        options: AIImportOptions,
     ) -> error::Result<ImportApprovedCardsResponse> {
+        let settings = self.get_ai_settings();
+        let target_deck_id = match &options.create_deck_from_source {
+            Some(source_name) => {
+                let name = deck_name_from_source(source_name)
+                    .or_invalid("the source name can't be used as a deck name")?;
+                self.get_or_create_normal_deck(&name)?.id
+            }
+            // no deck was provided, so fall back to the persisted default
+            None if target_deck_id.0 == 0 => settings
+                .default_deck
+                .or_invalid("no target deck was provided, and no default is set")?,
+            None => target_deck_id,
+        };
+        if let Some(allowed_decks) = &options.allowed_decks {
+            self.check_ai_deck_allowed(target_deck_id, allowed_decks)
+                .inspect_err(|_| debug!(deck_id = %target_deck_id, "import deck not allowed"))?;
+        }
+        if let Some(flag) = options.flag {
+            if flag >= 8 {
+                return Err(AiFlashcardsError::ValidationFailed {
+                    info: format!("{flag} is not a valid flag"),
+                }
+                .into());
+            }
+        }
+
         let mut imported_count = 0u32;
         let mut duplicate_count = 0u32;
+        let mut generated_card_count = 0u32;
+        let mut gated_count = 0u32;
         let mut errors: Vec<String> = Vec::new();
+        let mut warnings: Vec<String> = Vec::new();
+        let mut imported_notes = vec![];
+        let mut imported_card_ids = vec![];
+        let mut additional_tags = options.additional_tags;
+        additional_tags.extend(settings.base_tag.clone());
+        let config = ConvertConfig {
+            additional_tags,
+            provenance: options.provenance,
+            provenance_tags: options.provenance_tags,
+            guid_mode: options.guid_mode,
+            add_reverse_marker: options.add_reverse_marker,
+            back_footer_html: options.back_footer_html.as_deref().map(sanitize_card_html),
+            tag_map: options.tag_map,
+            strict_tag_map: options.strict_tag_map,
+            newlines_to_br: options.newlines_to_br,
+            collapse_whitespace: options.collapse_whitespace,
+            strip_surrounding_quotes: options.strip_surrounding_quotes,
+            trim_front: options.trim_front,
+            trim_back: options.trim_back,
+            auto_cloze_hint: options.auto_cloze_hint,
+            ..Default::default()
+        };
 
-        for card in cards {
-            // Only import approved cards
-            if card.status() != CardStatus::Approved {
-                continue;
+        // Only import approved cards
+        let mut cards: Vec<AIGeneratedCard> = cards
+            .into_iter()
+            .filter(|card| card.status() == CardStatus::Approved)
+            .map(AIGeneratedCard::from)
+            .map(|mut card| {
+                if card.notetype.is_none() {
+                    card.notetype = settings.notetype_for(card.card_type).map(Into::into);
+                }
+                card
+            })
+            .filter(|card| match &options.quality_gate {
+                Some(gate) if !gate.passes(card) => {
+                    debug!(card_id = %card.id, "card skipped by quality gate");
+                    gated_count += 1;
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        if options.skip_emptied_cards {
+            cards.retain(|card| {
+                let emptied = validate_card(card, &options.validation).emptied_fields;
+                if !emptied.is_empty() {
+                    debug!(card_id = %card.id, "card skipped, as sanitizing emptied it");
+                    errors.push(format!(
+                        "Card '{}' was not imported, as its {} only contained unsafe content",
+                        card.id,
+                        emptied.join(" and ")
+                    ));
+                }
+                emptied.is_empty()
+            });
+        }
+        // Resolve the notetype of each card, and ensure they all exist, before
+        // anything is written to the media folder or collection.
+        let reversed_name = default_notetype_name(CardType::BasicReversed);
+        let mut notetypes_to_add = vec![];
+        if options.reversed_fallback != ReversedFallback::Error
+            && cards
+                .iter()
+                .any(|card| card.card_type == CardType::BasicReversed && card.notetype.is_none())
+            && self.get_notetype_by_name(reversed_name)?.is_none()
+        {
+            match options.reversed_fallback {
+                ReversedFallback::Create => {
+                    notetypes_to_add.extend(self.missing_stock_notetypes([reversed_name])?)
+                }
+                ReversedFallback::Degrade => {
+                    debug!("splitting reversed cards, as the reversed notetype is missing");
+                    cards = cards
+                        .into_iter()
+                        .flat_map(|card| {
+                            if card.notetype.is_none() {
+                                expand_reversed(card)
+                            } else {
+                                vec![card]
+                            }
+                        })
+                        .collect();
+                }
+                ReversedFallback::Error => {}
+            }
+        }
+        if options.create_missing_notetypes {
+            let missing = self.missing_stock_notetypes(cards.iter().map(|card| {
+                card.notetype
+                    .as_deref()
+                    .unwrap_or_else(|| default_notetype_name(card.card_type))
+            }))?;
+            for notetype in missing {
+                if !notetypes_to_add
+                    .iter()
+                    .any(|added: &Notetype| added.name == notetype.name)
+                {
+                    notetypes_to_add.push(notetype);
+                }
+            }
+        }
+        for card in &mut cards {
+            if let Some(name) = &card.notetype {
+                if self.get_notetype_by_name(name)?.is_none()
+                    && !is_pending_notetype(&notetypes_to_add, name)
+                {
+                    debug!(card_id = %card.id, notetype = %name, "requested notetype missing");
+                    warnings.push(format!(
+                        "Card '{}' requested missing note type '{name}'; using '{}' instead",
+                        card.id,
+                        default_notetype_name(card.card_type)
+                    ));
+                    card.notetype = None;
+                }
             }
+        }
+        self.check_ai_notetypes_exist(
+            cards.iter().map(|card| {
+                (
+                    card.id.as_str(),
+                    card.notetype
+                        .as_deref()
+                        .unwrap_or_else(|| default_notetype_name(card.card_type)),
+                )
+            }),
+            &notetypes_to_add,
+        )?;
 
-            // Convert to ForeignNote
-            let foreign_note =
-                self.ai_card_to_foreign_note(&card, target_deck_id, &additional_tags);
+        self.import_ai_card_media(&mut cards, &options.media, &mut warnings)?;
+        if let Some(max) = options.max_cloze_deletions {
+            cards = apply_cloze_limit(
+                cards,
+                max,
+                options.cloze_overflow,
+                &mut warnings,
+                &mut errors,
+            );
+        }
+        self.add_stock_notetypes(notetypes_to_add)?;
+
+        let notes: Vec<(AIGeneratedCard, ForeignNote)> = cards
+            .into_iter()
+            .map(|card| {
+                let foreign_note = to_foreign_note(&card, target_deck_id, &config);
+                (card, foreign_note)
+            })
+            .collect();
 
+        let mut duplicates = if options.on_duplicate == OnDuplicate::Import {
+            DuplicateIndex::default()
+        } else {
+            self.ai_duplicate_index(target_deck_id)?
+        };
+        for (card, foreign_note) in notes {
             // Try to import
-            match self.import_single_ai_note(foreign_note) {
-                Ok(is_duplicate) => {
-                    if is_duplicate {
-                        duplicate_count += 1;
-                    } else {
-                        imported_count += 1;
+            match self.import_single_ai_note(
+                foreign_note,
+                &card,
+                &config,
+                options.on_duplicate,
+                &mut duplicates,
+                &mut warnings,
+            ) {
+                Ok(Some((note, card_count))) => {
+                    trace!(card_id = %card.id, note_id = %note.id, card_count, "card imported");
+                    imported_count += 1;
+                    generated_card_count += card_count as u32;
+                    self.suspend_ai_note_if_tagged(&note, &options.suspend_tags)?;
+                    if options.initial_interval.is_some() || options.flag.is_some() {
+                        imported_card_ids.extend(
+                            self.storage
+                                .all_cards_of_note(note.id)?
+                                .into_iter()
+                                .map(|card| card.id),
+                        );
                     }
+                    imported_notes.push(ImportedNote::new(&note));
+                }
+                Ok(None) => {
+                    debug!(card_id = %card.id, "card skipped as duplicate");
+                    duplicate_count += 1;
                 }
                 Err(e) => {
-                    errors.push(format!("Failed to import card '{}': {}", card.id, e));
+                    warn!(card_id = %card.id, error = %e, "card import failed");
+                    errors.push(format!("Failed to import card '{}': {e}", card.id));
                 }
             }
         }
 
+        if let Some(days) = options.initial_interval {
+            let spec = parse_due_date_str(&format!("{days}!"))?;
+            self.set_due_date_inner(&imported_card_ids, &spec)?;
+        }
+        if let Some(flag) = options.flag {
+            let usn = self.usn()?;
+            self.set_card_flag_inner(&imported_card_ids, flag, usn)?;
+        }
+
+        if let Some(session_id) = &options.session_id {
+            self.record_session_import(session_id, imported_notes)?;
+        }
+
         Ok(ImportApprovedCardsResponse {
             changes: Some(anki_proto::collection::OpChanges {
                 card: true,
@@ -177,54 +832,212 @@ impl Collection {
             imported_count,
             duplicate_count,
             errors,
+            generated_card_count,
+            gated_count,
+            warnings,
+            deck_id: target_deck_id.0,
+        })
+    }
+
+    /// Copy the provided media referenced by the cards into the media folder,
+    /// and point the references at the names the files were stored under.
+    /// References to files that were neither provided nor already present are
+    /// reported in `warnings`. Files are not removed if the import later
+    /// fails; Check Media will clean them up.
+    fn import_ai_card_media(
+        &self,
+        cards: &mut [AIGeneratedCard],
+        media: &HashMap<String, MediaSource>,
+        warnings: &mut Vec<String>,
+    ) -> error::Result<()> {
+        let mgr = self.media()?;
+        // referenced name -> stored name, or None if unavailable
+        let mut stored: HashMap<String, Option<String>> = HashMap::new();
+        for card in cards {
+            for text in [&mut card.front, &mut card.back, &mut card.reference] {
+                let replaced = replace_media_refs(text, |name| {
+                    if is_remote_media(name) {
+                        return None;
+                    }
+                    stored
+                        .entry(name.to_string())
+                        .or_insert_with(|| match media.get(name) {
+                            Some(source) => {
+                                match source.read().and_then(|data| {
+                                    mgr.add_file(name, &data).map(|n| n.into_owned())
+                                }) {
+                                    Ok(stored_name) => Some(stored_name),
+                                    Err(e) => {
+                                        warnings.push(format!(
+                                            "Failed to add media file '{name}': {e}"
+                                        ));
+                                        None
+                                    }
+                                }
+                            }
+                            None => {
+                                if !self.media_folder.join(name).exists() {
+                                    warnings.push(format!(
+                                        "Media file '{name}' is referenced but was not provided"
+                                    ));
+                                }
+                                None
+                            }
+                        })
+                        .clone()
+                });
+                if let Some(replaced) = replaced {
+                    *text = replaced;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remember the notes created by a session's import, in addition to any
+    /// recorded by previous imports from the same session.
+    fn record_session_import(
+        &mut self,
+        session_id: &str,
+        notes: Vec<ImportedNote>,
+    ) -> error::Result<()> {
+        let mut imports: HashMap<String, Vec<ImportedNote>> =
+            self.get_config_default(SESSION_IMPORTS_KEY);
+        imports
+            .entry(session_id.to_string())
+            .or_default()
+            .extend(notes);
+        self.set_config(SESSION_IMPORTS_KEY, &imports)?;
+        Ok(())
+    }
+
+    /// Remove the notes created by a session's imports. If `keep_edited` is
+    /// true, notes the user has modified since the import are kept. Returns
+    /// the number of removed notes.
+    pub fn delete_session_import(
+        &mut self,
+        session_id: &str,
+        keep_edited: bool,
+    ) -> error::Result<OpOutput<usize>> {
+        self.transact(Op::RemoveNote, |col| {
+            let mut imports: HashMap<String, Vec<ImportedNote>> =
+                col.get_config_default(SESSION_IMPORTS_KEY);
+            let imported = imports.remove(session_id).or_not_found(session_id)?;
+            let mut nids = vec![];
+            for imported in imported {
+                if let Some(note) = col.storage.get_note(imported.id)? {
+                    if !keep_edited || note_content_checksum(&note) == imported.checksum {
+                        nids.push(note.id);
+                    }
+                }
+            }
+            let usn = col.usn()?;
+            col.remove_notes_inner(&nids, usn)?;
+            col.set_config(SESSION_IMPORTS_KEY, &imports)?;
+            Ok(nids.len())
         })
     }
 
-    /// Convert an AI-generated card to a ForeignNote for import
-    fn ai_card_to_foreign_note(
+    /// Ensure the target deck is in the allowlist, matching listed names
+    /// against the deck's current name.
+    fn check_ai_deck_allowed(
         &self,
-        card: &GeneratedCard,
-        deck_id: DeckId,
-        additional_tags: &[String],
-    ) -> ForeignNote {
-        // Combine AI suggested tags with additional tags and auto-tags
-        let mut all_tags: Vec<String> = vec!["ai-generated".to_string()];
-        all_tags.extend(card.suggested_tags.iter().cloned());
-        all_tags.extend(additional_tags.iter().cloned());
-
-        // Determine notetype name based on card type
-        let notetype_name = match card.card_type() {
-            CardType::Basic => "Basic",
-            CardType::BasicReversed => "Basic (and reversed card)",
-            CardType::Cloze => "Cloze",
-        };
-
-        // Create fields based on card type
-        let fields = match card.card_type() {
-            CardType::Cloze => vec![
-                Some(card.front.clone()), // Text field (with cloze deletions)
-                Some(card.back.clone()),  // Extra field (usually empty for cloze)
-            ],
-            _ => vec![
-                Some(card.front.clone()), // Front field
-                Some(card.back.clone()),  // Back field
-            ],
-        };
-
-        ForeignNote {
-            guid: String::new(), // Will be auto-generated
-            fields,
-            tags: Some(all_tags),
-            notetype: NameOrId::Name(notetype_name.to_string()),
-            deck: NameOrId::Id(deck_id.0),
-            cards: Vec::new(),
-        }
-    }
-
-    /// Import a single note, returning whether it was a duplicate
-    fn import_single_ai_note(&mut self, foreign_note: ForeignNote) -> error::Result<bool> {
-        use crate::notes::Note;
+        target_deck_id: DeckId,
+        allowed_decks: &[NameOrId],
+    ) -> error::Result<()> {
+        for deck in allowed_decks {
+            let allowed_id = match deck {
+                NameOrId::Id(id) => Some(DeckId(*id)),
+                NameOrId::Name(name) => self.get_deck_id(name)?,
+            };
+            if allowed_id == Some(target_deck_id) {
+                return Ok(());
+            }
+        }
+        Err(AiFlashcardsError::DeckNotAllowed {
+            deck_id: target_deck_id,
+        }
+        .into())
+    }
+
+    /// Ensure every notetype required by an import exists, so that missing
+    /// notetypes are reported together before anything is imported. Takes
+    /// each card's id along with its notetype name. Notetypes in `pending` are
+    /// about to be added, so are not reported.
+    fn check_ai_notetypes_exist<'a>(
+        &mut self,
+        notetypes: impl IntoIterator<Item = (&'a str, &'a str)>,
+        pending: &[Notetype],
+    ) -> error::Result<()> {
+        let mut exists: HashMap<&str, bool> = HashMap::new();
+        let mut missing = vec![];
+        for (card_id, notetype) in notetypes {
+            let found = match exists.get(notetype) {
+                Some(found) => *found,
+                None => {
+                    let found = is_pending_notetype(pending, notetype)
+                        || self.get_notetype_by_name(notetype)?.is_some();
+                    exists.insert(notetype, found);
+                    if !found {
+                        missing.push(notetype.to_string());
+                    }
+                    found
+                }
+            };
+            if !found {
+                debug!(card_id, notetype, "notetype missing");
+            }
+        }
+        if !missing.is_empty() {
+            return Err(AiFlashcardsError::NotetypeMissing { names: missing }.into());
+        }
+        Ok(())
+    }
+
+    /// The stock notetypes matching any of the given names that are missing
+    /// from the collection. Nothing is added.
+    fn missing_stock_notetypes<'a>(
+        &mut self,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> error::Result<Vec<Notetype>> {
+        let mut missing = vec![];
+        for name in names.into_iter().unique() {
+            if self.get_notetype_by_name(name)?.is_some() {
+                continue;
+            }
+            if let Some(notetype) = all_stock_notetypes(&self.tr)
+                .into_iter()
+                .find(|nt| nt.name.eq_ignore_ascii_case(name))
+            {
+                if !missing.iter().any(|nt: &Notetype| nt.name == notetype.name) {
+                    missing.push(notetype);
+                }
+            }
+        }
+        Ok(missing)
+    }
 
+    /// Add stock notetypes returned by [Self::missing_stock_notetypes].
+    fn add_stock_notetypes(&mut self, notetypes: Vec<Notetype>) -> error::Result<()> {
+        let usn = self.usn()?;
+        for mut notetype in notetypes {
+            debug!(notetype = %notetype.name, "adding missing stock notetype");
+            self.add_notetype_inner(&mut notetype, usn, false)?;
+        }
+        Ok(())
+    }
+
+    /// Import a single note, returning the added note and the number of cards
+    /// it generated, or None if it was a duplicate that wasn't imported
+    fn import_single_ai_note(
+        &mut self,
+        foreign_note: ForeignNote,
+        card: &AIGeneratedCard,
+        config: &ConvertConfig,
+        on_duplicate: OnDuplicate,
+        duplicates: &mut DuplicateIndex,
+        warnings: &mut Vec<String>,
+    ) -> error::Result<Option<(Note, usize)>> {
         // Get the notetype by name
         let notetype_name = match &foreign_note.notetype {
             NameOrId::Name(name) => name.clone(),
@@ -243,108 +1056,149 @@ impl Collection {
 
         // Create a new note with the notetype
         let mut note = Note::new(&notetype);
+        if !foreign_note.guid.is_empty() {
+            note.guid = foreign_note.guid.clone();
+        }
 
         // Set the fields
-        for (idx, field_opt) in foreign_note.fields.iter().enumerate() {
-            if let Some(field_content) = field_opt {
-                if idx < note.fields().len() {
-                    note.set_field(idx, field_content.clone())?;
-                }
+        for (ord, field) in fields_for_notetype(&foreign_note.fields, &notetype)
+            .into_iter()
+            .enumerate()
+        {
+            if let Some(field) = field {
+                note.set_field(ord, field)?;
             }
         }
 
+        for field in missing_cloze_fields(card, &notetype) {
+            warnings.push(format!(
+                "Card '{}': notetype '{}' has no '{field}' field",
+                card.id, notetype.name
+            ));
+        }
+
+        config.apply_reference(&mut note, &notetype, &card.reference)?;
+        config.apply_examples(&mut note, &notetype, &card.examples)?;
+        config.apply_back_footer(&mut note, &notetype)?;
+        config.apply_notes(
+            &mut note,
+            &notetype,
+            card.notes.as_deref().unwrap_or_default(),
+        )?;
+        config.apply_add_reverse(&mut note, &notetype, card.card_type)?;
+        config.apply_provenance(&mut note, &notetype)?;
+
         // Set tags
         if let Some(tags) = foreign_note.tags {
             note.tags = tags;
         }
 
-        // Add the note (this also generates cards)
-        self.add_note_inner(&mut note, deck_id)?;
+        if on_duplicate != OnDuplicate::Import {
+            if let Some(existing_id) = duplicates.find(&note) {
+                if on_duplicate == OnDuplicate::MergeTags {
+                    self.merge_tags_into_note(existing_id, &note.tags)?;
+                }
+                return Ok(None);
+            }
+        }
 
-        // Note: Duplicate detection could be added here by checking checksums
-        // before adding. For now, we always add the note.
-        Ok(false) // Not a duplicate
+        // Add the note (this also generates cards)
+        let card_count = self.add_note_inner(&mut note, deck_id)?;
+        if on_duplicate != OnDuplicate::Import {
+            duplicates.insert(note.notetype_id, &note.fields()[0], note.id);
+        }
+        Ok(Some((note, card_count)))
     }
 
-    /// Save AI session to disk
-    pub fn save_ai_session(&self, request: SaveSessionRequest) -> error::Result<()> {
-        let session = SessionFile {
-            version: SESSION_VERSION,
-            created_timestamp: TimestampSecs::now().0,
-            source_name: request.source_name,
-            source_text: request.source_text,
-            cards: request.cards.iter().map(SessionCard::from).collect(),
-        };
-
-        let json = serde_json::to_string_pretty(&session)?;
-        fs::write(self.ai_session_path(), json)?;
-
+    /// Suspend the cards of a note with any of the provided tags.
+    fn suspend_ai_note_if_tagged(&mut self, note: &Note, tags: &[String]) -> error::Result<()> {
+        if note
+            .tags
+            .iter()
+            .any(|tag| tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+        {
+            debug!(note_id = %note.id, "suspending tagged note");
+            let cards = self.storage.all_cards_of_note(note.id)?;
+            self.bury_or_suspend_cards_inner(cards, BuryOrSuspendMode::Suspend)?;
+        }
         Ok(())
     }
 
-    /// Load AI session from disk
-    pub fn load_ai_session(&self) -> error::Result<LoadSessionResponse> {
-        let path = self.ai_session_path();
-
-        if !path.exists() {
-            return Ok(LoadSessionResponse {
-                has_session: false,
-                cards: Vec::new(),
-                source_name: String::new(),
-                created_timestamp: 0,
-                source_text: String::new(),
-            });
-        }
-
-        let json = fs::read_to_string(&path)?;
-        let session: SessionFile = serde_json::from_str(&json)?;
-
-        // Check version compatibility
-        if session.version != SESSION_VERSION {
-            // Clear incompatible session
-            let _ = fs::remove_file(&path);
-            return Ok(LoadSessionResponse {
-                has_session: false,
-                cards: Vec::new(),
-                source_name: String::new(),
-                created_timestamp: 0,
-                source_text: String::new(),
-            });
+    /// Index the notes of the deck, so imported cards can be checked for
+    /// duplicates.
+    fn ai_duplicate_index(&self, deck_id: DeckId) -> error::Result<DuplicateIndex> {
+        let mut index = DuplicateIndex::default();
+        for (nid, ntid, first_field) in self.storage.first_fields_of_notes_in_deck(deck_id)? {
+            index.insert(ntid, &first_field, nid);
         }
+        Ok(index)
+    }
 
-        // Check if session has expired
-        let age = TimestampSecs::now().0 - session.created_timestamp;
-        if age > SESSION_MAX_AGE_SECS {
-            // Clear expired session
-            let _ = fs::remove_file(&path);
-            return Ok(LoadSessionResponse {
-                has_session: false,
-                cards: Vec::new(),
-                source_name: String::new(),
-                created_timestamp: 0,
-                source_text: String::new(),
-            });
+    /// Add any of the provided tags the note doesn't already have.
+    fn merge_tags_into_note(&mut self, nid: NoteId, tags: &[String]) -> error::Result<()> {
+        let mut note = self.storage.get_note(nid)?.or_not_found(nid)?;
+        for tag in tags {
+            if !note.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+                note.tags.push(tag.clone());
+            }
         }
+        self.update_note_inner(&mut note)
+    }
 
-        Ok(LoadSessionResponse {
-            has_session: true,
-            cards: session.cards.into_iter().map(Into::into).collect(),
-            source_name: session.source_name,
-            created_timestamp: session.created_timestamp,
-            source_text: session.source_text,
-        })
+    /// The ids of notes with the provided tag or one of its children, such as
+    /// [AI_GENERATED_TAG], in ascending order. They can be passed to
+    /// [Collection::notes_to_ai_cards], or selected in the browser.
+    pub fn find_ai_generated_notes(&mut self, base_tag: &str) -> error::Result<Vec<NoteId>> {
+        require!(!base_tag.trim().is_empty(), "a tag must be provided");
+        let mut nids = self.search_notes_unordered(SearchNode::from_tag_name(base_tag))?;
+        nids.sort_unstable();
+        Ok(nids)
     }
 
-    /// Clear AI session from disk
-    pub fn clear_ai_session(&self) -> error::Result<()> {
-        let path = self.ai_session_path();
-        if path.exists() {
-            fs::remove_file(path)?;
+    /// Rebuild AI cards from previously imported notes, so they can be
+    /// reviewed and edited again. The card type is inferred from the notetype.
+    pub fn notes_to_ai_cards(
+        &mut self,
+        note_ids: Vec<NoteId>,
+    ) -> error::Result<Vec<AIGeneratedCard>> {
+        let mut cards = Vec::with_capacity(note_ids.len());
+        for nid in note_ids {
+            let note = self.storage.get_note(nid)?.or_not_found(nid)?;
+            let notetype = self
+                .get_notetype(note.notetype_id)?
+                .or_not_found(note.notetype_id)?;
+            let card_type = if notetype.is_cloze() {
+                CardType::Cloze
+            } else if notetype.templates.len() > 1 {
+                CardType::BasicReversed
+            } else {
+                CardType::Basic
+            };
+            let ords = target_field_ords(&notetype, 2);
+            let field = |ord: usize| note.fields().get(ord).cloned().unwrap_or_default();
+            cards.push(AIGeneratedCard {
+                id: nid.to_string(),
+                card_type,
+                front: field(ords[0]),
+                back: field(ords[1]),
+                suggested_tags: note
+                    .tags
+                    .iter()
+                    .filter(|tag| !tag.eq_ignore_ascii_case(AI_GENERATED_TAG))
+                    .cloned()
+                    .collect(),
+                status: CardStatus::Pending,
+                ..Default::default()
+            });
         }
-        Ok(())
+        Ok(cards)
     }
 }
 
+fn is_remote_media(name: &str) -> bool {
+    name.contains("://") || name.starts_with("data:")
+}
+
 impl crate::services::AIFlashcardsService for Collection {
     fn test_api_connection(
         &mut self,
@@ -399,22 +1253,957 @@ impl crate::services::AIFlashcardsService for Collection {
         &mut self,
         input: ImportApprovedCardsRequest,
     ) -> error::Result<ImportApprovedCardsResponse> {
-        self.import_ai_cards(
-            input.cards,
-            DeckId(input.target_deck_id),
-            input.additional_tags,
-        )
+        let options = AIImportOptions::from(&input);
+        self.import_ai_cards(input.cards, DeckId(input.target_deck_id), options)
     }
 
-    fn save_session(&mut self, input: SaveSessionRequest) -> error::Result<()> {
+    fn save_session(&mut self, input: SaveSessionRequest) -> error::Result<SaveSessionResponse> {
         self.save_ai_session(input)
     }
 
-    fn load_session(&mut self) -> error::Result<LoadSessionResponse> {
-        self.load_ai_session()
+    fn load_session(&mut self, input: LoadSessionRequest) -> error::Result<LoadSessionResponse> {
+        self.load_ai_session(input)
     }
 
     fn clear_session(&mut self) -> error::Result<()> {
         self.clear_ai_session()
     }
 }
+
+/// True if one of the notetypes about to be added has the given name.
+fn is_pending_notetype(pending: &[Notetype], name: &str) -> bool {
+    pending.iter().any(|nt| nt.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::ai_flashcards::convert::AI_META_FIELD;
+    use crate::ai_flashcards::test::approved_card;
+    use crate::card::CardQueue;
+    use crate::tests::open_fs_test_collection;
+    use crate::tests::DeckAdder;
+
+    /// Collects formatted log output, so tests can check what was logged.
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogCapture {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn missing_notetypes_are_reported_before_import() {
+        let mut col = Collection::new();
+        let cloze = col
+            .get_notetype_by_name(default_notetype_name(CardType::Cloze))
+            .unwrap()
+            .unwrap();
+        col.remove_notetype(cloze.id).unwrap();
+
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q", "A"),
+            approved_card("2", CardType::Cloze, "{{c1::A}}", ""),
+            approved_card("3", CardType::Cloze, "{{c1::B}}", ""),
+        ];
+        let err = col
+            .import_ai_cards(cards, DeckId(1), Default::default())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            AnkiError::AiFlashcardsError {
+                source: AiFlashcardsError::NotetypeMissing {
+                    names: vec!["Cloze".to_string()]
+                }
+            }
+        );
+        assert!(err.message(&col.tr).contains("Cloze"));
+        assert!(col.get_all_notes().is_empty());
+    }
+
+    #[test]
+    fn imported_notes_can_be_converted_back_to_cards() {
+        let mut col = Collection::new();
+        let mut cloze = approved_card("2", CardType::Cloze, "{{c1::Paris}} is in France", "");
+        cloze.suggested_tags = vec!["geography".to_string()];
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q", "A"),
+            cloze,
+            approved_card("3", CardType::BasicReversed, "front", "back"),
+        ];
+        col.import_ai_cards(cards, DeckId(1), Default::default())
+            .unwrap();
+
+        let mut nids: Vec<NoteId> = col.get_all_notes().iter().map(|n| n.id).collect();
+        nids.sort();
+        let cards = col.notes_to_ai_cards(nids.clone()).unwrap();
+        assert_eq!(cards.len(), 3);
+        assert_eq!(cards[0].card_type, CardType::Basic);
+        assert_eq!(
+            (cards[0].front.as_str(), cards[0].back.as_str()),
+            ("Q", "A")
+        );
+        assert_eq!(cards[1].card_type, CardType::Cloze);
+        assert_eq!(cards[1].front, "{{c1::Paris}} is in France");
+        assert_eq!(cards[1].suggested_tags, ["geography"]);
+        assert_eq!(cards[2].card_type, CardType::BasicReversed);
+        assert_eq!(cards[2].id, nids[2].to_string());
+        assert!(cards.iter().all(|c| c.status == CardStatus::Pending));
+    }
+
+    #[test]
+    fn generated_cards_are_counted_separately_from_notes() {
+        let mut col = Collection::new();
+        let cards = vec![approved_card(
+            "1",
+            CardType::Cloze,
+            "{{c1::Paris}} is the capital of {{c2::France}}",
+            "",
+        )];
+        let response = col
+            .import_ai_cards(cards, DeckId(1), Default::default())
+            .unwrap();
+        assert_eq!(response.imported_count, 1);
+        assert_eq!(response.generated_card_count, 2);
+    }
+
+    #[test]
+    fn imports_are_limited_to_allowed_decks() {
+        let mut col = Collection::new();
+        let allowed = DeckAdder::new("Allowed").add(&mut col);
+        let other = DeckAdder::new("Other").add(&mut col);
+        let options = AIImportOptions {
+            allowed_decks: Some(vec![NameOrId::Name("Allowed".to_string()), NameOrId::Id(1)]),
+            ..Default::default()
+        };
+        let cards = vec![approved_card("1", CardType::Basic, "Q", "A")];
+
+        let err = col
+            .import_ai_cards(cards.clone(), other.id, options.clone())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            AnkiError::AiFlashcardsError {
+                source: AiFlashcardsError::DeckNotAllowed { deck_id: other.id }
+            }
+        );
+        assert!(col.get_all_notes().is_empty());
+
+        // chunked imports fail the same way, instead of reporting no cards
+        let chunked = AIImportOptions {
+            chunk_size: Some(1),
+            ..options.clone()
+        };
+        assert_eq!(
+            col.import_ai_cards(cards.clone(), other.id, chunked)
+                .unwrap_err(),
+            err
+        );
+
+        col.import_ai_cards(cards.clone(), allowed.id, options.clone())
+            .unwrap();
+        col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(col.get_all_notes().len(), 2);
+    }
+
+    #[test]
+    fn provenance_is_recorded_in_meta_field() {
+        let mut col = Collection::new();
+        let mut basic = (*col
+            .get_notetype_by_name(default_notetype_name(CardType::Basic))
+            .unwrap()
+            .unwrap())
+        .clone();
+        basic.add_field(AI_META_FIELD);
+        col.update_notetype(&mut basic, false).unwrap();
+
+        let options = AIImportOptions {
+            provenance: Some(Provenance {
+                model: "gpt-4o".to_string(),
+                source: "notes.pdf".to_string(),
+                timestamp: 1_700_000_000,
+            }),
+            ..Default::default()
+        };
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q", "A"),
+            // notetypes without the field are unaffected
+            approved_card("2", CardType::BasicReversed, "Q2", "A2"),
+        ];
+        let response = col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(response.imported_count, 2);
+
+        let note = col
+            .get_all_notes()
+            .into_iter()
+            .find(|note| note.notetype_id == basic.id)
+            .unwrap();
+        let meta: serde_json::Value = serde_json::from_str(&note.fields()[2]).unwrap();
+        assert_eq!(meta["model"], "gpt-4o");
+        assert_eq!(meta["source"], "notes.pdf");
+        assert_eq!(meta["timestamp"], 1_700_000_000);
+    }
+
+    #[test]
+    fn session_imports_can_be_deleted() {
+        let mut col = Collection::new();
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q", "A"),
+            approved_card("2", CardType::Basic, "Q2", "A2"),
+        ];
+        let options = AIImportOptions {
+            session_id: Some("session".to_string()),
+            ..Default::default()
+        };
+        col.import_ai_cards(cards.clone(), DeckId(1), options.clone())
+            .unwrap();
+        // notes imported without the session aren't affected
+        col.import_ai_cards(cards.clone(), DeckId(1), Default::default())
+            .unwrap();
+        assert_eq!(col.get_all_notes().len(), 4);
+
+        let removed = col.delete_session_import("session", false).unwrap().output;
+        assert_eq!(removed, 2);
+        assert_eq!(col.get_all_notes().len(), 2);
+        assert!(col.delete_session_import("session", false).is_err());
+
+        // the removal can be undone
+        col.undo().unwrap();
+        assert_eq!(col.get_all_notes().len(), 4);
+    }
+
+    #[test]
+    fn edited_session_notes_can_be_kept() {
+        let mut col = Collection::new();
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q", "A"),
+            approved_card("2", CardType::Basic, "Q2", "A2"),
+        ];
+        let options = AIImportOptions {
+            session_id: Some("session".to_string()),
+            ..Default::default()
+        };
+        col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        let mut note = col.get_all_notes().pop().unwrap();
+        note.set_field(1, "edited").unwrap();
+        col.update_note(&mut note).unwrap();
+
+        let removed = col.delete_session_import("session", true).unwrap().output;
+        assert_eq!(removed, 1);
+        let remaining = col.get_all_notes();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].fields()[1], "edited");
+    }
+
+    #[test]
+    fn failed_chunks_keep_earlier_chunks() {
+        let mut col = Collection::new();
+        let cloze = col
+            .get_notetype_by_name(default_notetype_name(CardType::Cloze))
+            .unwrap()
+            .unwrap();
+        col.remove_notetype(cloze.id).unwrap();
+
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q", "A"),
+            approved_card("2", CardType::Basic, "Q2", "A2"),
+            approved_card("3", CardType::Basic, "Q3", "A3"),
+            approved_card("4", CardType::Cloze, "{{c1::A}}", ""),
+            approved_card("5", CardType::Basic, "Q5", "A5"),
+        ];
+        let options = AIImportOptions {
+            chunk_size: Some(2),
+            ..Default::default()
+        };
+        let response = col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(response.imported_count, 2);
+        assert_eq!(response.errors.len(), 1);
+        assert!(response.errors[0].contains("chunk 2"));
+        assert!(response.errors[0].contains("'3' to '4'"));
+        // the failed chunk was rolled back, and later chunks were skipped
+        assert_eq!(col.get_all_notes().len(), 2);
+    }
+
+    #[test]
+    fn quality_gate_skips_weak_cards() {
+        let mut col = Collection::new();
+        let with_confidence = |mut card: GeneratedCard, confidence| {
+            card.confidence = confidence;
+            card
+        };
+        let cards = vec![
+            with_confidence(approved_card("1", CardType::Basic, "Q", "A"), Some(0.9)),
+            with_confidence(approved_card("2", CardType::Basic, "Q2", "A2"), Some(0.5)),
+            with_confidence(approved_card("3", CardType::Basic, "Q3", "A3"), None),
+            // passes the threshold, but has a warning
+            with_confidence(
+                approved_card("4", CardType::Basic, "{{c1::Q4}}", "A4"),
+                Some(0.9),
+            ),
+        ];
+        let options = AIImportOptions {
+            quality_gate: Some(QualityGate {
+                min_confidence: 0.7,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let response = col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(response.imported_count, 1);
+        assert_eq!(response.gated_count, 3);
+        assert_eq!(col.get_all_notes()[0].fields()[0], "Q");
+    }
+
+    #[test]
+    fn provided_media_is_copied_and_references_updated() {
+        let (mut col, _dir) = open_fs_test_collection("media");
+        // an unrelated file already uses the name, so the new one is renamed
+        col.media()
+            .unwrap()
+            .add_file("diagram.png", b"existing")
+            .unwrap();
+
+        let options = AIImportOptions {
+            media: HashMap::from([(
+                "diagram.png".to_string(),
+                MediaSource::Data(b"diagram".to_vec()),
+            )]),
+            ..Default::default()
+        };
+        let cards = vec![approved_card(
+            "1",
+            CardType::Basic,
+            r#"<img src="diagram.png"> <img src="missing.png">"#,
+            "A",
+        )];
+        let response = col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(response.imported_count, 1);
+        assert_eq!(response.warnings.len(), 1);
+        assert!(response.warnings[0].contains("missing.png"));
+
+        let front = col.get_all_notes()[0].fields()[0].clone();
+        let stored_name = front
+            .strip_prefix(r#"<img src=""#)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+        assert_ne!(stored_name, "diagram.png");
+        assert_eq!(
+            std::fs::read(col.media_folder.join(stored_name)).unwrap(),
+            b"diagram"
+        );
+        assert!(front.ends_with(r#"<img src="missing.png">"#));
+    }
+
+    #[test]
+    fn media_is_not_copied_if_notetypes_are_missing() {
+        let (mut col, _dir) = open_fs_test_collection("media_missing_notetype");
+        let cloze = col
+            .get_notetype_by_name(default_notetype_name(CardType::Cloze))
+            .unwrap()
+            .unwrap();
+        col.remove_notetype(cloze.id).unwrap();
+
+        let options = AIImportOptions {
+            media: HashMap::from([(
+                "diagram.png".to_string(),
+                MediaSource::Data(b"diagram".to_vec()),
+            )]),
+            ..Default::default()
+        };
+        let cards = vec![approved_card(
+            "1",
+            CardType::Cloze,
+            r#"{{c1::A}} <img src="diagram.png">"#,
+            "",
+        )];
+        col.import_ai_cards(cards, DeckId(1), options).unwrap_err();
+        assert_eq!(std::fs::read_dir(&col.media_folder).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn fields_are_matched_by_name() {
+        let mut col = Collection::new();
+        let mut reversed = (*col
+            .get_notetype_by_name(default_notetype_name(CardType::BasicReversed))
+            .unwrap()
+            .unwrap())
+        .clone();
+        reversed.fields.swap(0, 1);
+        col.update_notetype(&mut reversed, false).unwrap();
+
+        let cards = vec![approved_card("1", CardType::BasicReversed, "Q", "A")];
+        col.import_ai_cards(cards, DeckId(1), Default::default())
+            .unwrap();
+        let note = col.get_all_notes().pop().unwrap();
+        assert_eq!(note.fields(), &["A", "Q"]);
+    }
+
+    #[test]
+    fn skipped_cards_are_logged() {
+        let mut col = Collection::new();
+        let capture = LogCapture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .finish();
+
+        let cards = vec![approved_card("weak-card", CardType::Basic, "Q", "A")];
+        let options = AIImportOptions {
+            quality_gate: Some(QualityGate {
+                min_confidence: 0.5,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        });
+
+        let logs = capture.contents();
+        assert!(logs.contains("card skipped by quality gate"));
+        assert!(logs.contains("card_id=weak-card"));
+    }
+
+    #[test]
+    fn keyed_imports_are_only_applied_once() {
+        let mut col = Collection::new();
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q", "A"),
+            approved_card("2", CardType::Cloze, "{{c1::A}} and {{c2::B}}", ""),
+        ];
+        let options = AIImportOptions {
+            idempotency_key: Some("request-1".to_string()),
+            ..Default::default()
+        };
+        let first = col
+            .import_ai_cards(cards.clone(), DeckId(1), options.clone())
+            .unwrap();
+        let retried = col
+            .import_ai_cards(cards.clone(), DeckId(1), options)
+            .unwrap();
+        assert_eq!(first, retried);
+        assert_eq!(retried.imported_count, 2);
+        assert_eq!(col.get_all_notes().len(), 2);
+
+        // a different key is a new import
+        let options = AIImportOptions {
+            idempotency_key: Some("request-2".to_string()),
+            ..Default::default()
+        };
+        col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(col.get_all_notes().len(), 4);
+    }
+
+    #[test]
+    fn suggested_tags_are_mapped() {
+        let mut col = Collection::new();
+        let mut card = approved_card("1", CardType::Basic, "Q", "A");
+        card.suggested_tags = vec!["bio".to_string(), "junk".to_string(), "cells".to_string()];
+        let request = ImportApprovedCardsRequest {
+            cards: vec![card],
+            target_deck_id: 1,
+            tag_map: HashMap::from([
+                ("bio".to_string(), "biology".to_string()),
+                ("junk".to_string(), String::new()),
+            ]),
+            ..Default::default()
+        };
+        crate::services::AIFlashcardsService::import_approved_cards(&mut col, request.clone())
+            .unwrap();
+        assert_eq!(
+            col.get_all_notes()[0].tags,
+            ["ai-generated", "biology", "cells"]
+        );
+
+        let request = ImportApprovedCardsRequest {
+            strict_tag_map: true,
+            ..request
+        };
+        crate::services::AIFlashcardsService::import_approved_cards(&mut col, request).unwrap();
+        let notes = col.get_all_notes();
+        assert!(notes
+            .iter()
+            .any(|note| note.tags == ["ai-generated", "biology"]));
+    }
+
+    #[test]
+    fn newlines_can_be_converted_on_import() {
+        let mut col = Collection::new();
+        let request = ImportApprovedCardsRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "line 1\nline 2")],
+            target_deck_id: 1,
+            newlines_to_br: true,
+            ..Default::default()
+        };
+        crate::services::AIFlashcardsService::import_approved_cards(&mut col, request).unwrap();
+        assert_eq!(col.get_all_notes()[0].fields()[1], "line 1<br>line 2");
+    }
+
+    #[test]
+    fn quotes_and_whitespace_can_be_cleaned_on_import() {
+        let mut col = Collection::new();
+        let request = ImportApprovedCardsRequest {
+            cards: vec![approved_card("1", CardType::Basic, "\"Q\"", "a  \t b")],
+            target_deck_id: 1,
+            collapse_whitespace: true,
+            strip_surrounding_quotes: true,
+            ..Default::default()
+        };
+        crate::services::AIFlashcardsService::import_approved_cards(&mut col, request).unwrap();
+        assert_eq!(col.get_all_notes()[0].fields(), &["Q", "a b"]);
+    }
+
+    #[test]
+    fn fields_can_be_trimmed_on_import() {
+        let mut col = Collection::new();
+        let request = ImportApprovedCardsRequest {
+            cards: vec![approved_card("1", CardType::Basic, " Q\n", "  code")],
+            target_deck_id: 1,
+            trim_front: true,
+            ..Default::default()
+        };
+        crate::services::AIFlashcardsService::import_approved_cards(&mut col, request).unwrap();
+        assert_eq!(col.get_all_notes()[0].fields(), &["Q", "  code"]);
+    }
+
+    #[test]
+    fn cloze_hints_can_be_added_on_import() {
+        let mut col = Collection::new();
+        let request = ImportApprovedCardsRequest {
+            cards: vec![approved_card("1", CardType::Cloze, "{{c1::Paris}}", "")],
+            target_deck_id: 1,
+            auto_cloze_hint: true,
+            ..Default::default()
+        };
+        crate::services::AIFlashcardsService::import_approved_cards(&mut col, request).unwrap();
+        assert_eq!(col.get_all_notes()[0].fields()[0], "{{c1::Paris::P...}}");
+    }
+
+    #[test]
+    fn duplicate_tags_can_be_merged() {
+        let mut col = Collection::new();
+        let mut card = approved_card("1", CardType::Basic, "Q", "A");
+        card.suggested_tags = vec!["biology".to_string()];
+        col.import_ai_cards(vec![card.clone()], DeckId(1), Default::default())
+            .unwrap();
+
+        card.suggested_tags = vec!["biology".to_string(), "cells".to_string()];
+        let options = AIImportOptions {
+            on_duplicate: OnDuplicate::MergeTags,
+            ..Default::default()
+        };
+        let response = col
+            .import_ai_cards(
+                vec![card, approved_card("2", CardType::Basic, "Q2", "A2")],
+                DeckId(1),
+                options,
+            )
+            .unwrap();
+        assert_eq!(response.imported_count, 1);
+        assert_eq!(response.duplicate_count, 1);
+
+        let notes = col.get_all_notes();
+        assert_eq!(notes.len(), 2);
+        let existing = notes.iter().find(|n| n.fields()[0] == "Q").unwrap();
+        assert_eq!(existing.tags, ["ai-generated", "biology", "cells"]);
+    }
+
+    #[test]
+    fn duplicates_are_found_in_target_deck() {
+        let mut col = Collection::new();
+        let deck = DeckAdder::new("Biology").add(&mut col);
+        let existing: Vec<GeneratedCard> = (0..20)
+            .map(|n| approved_card(&n.to_string(), CardType::Basic, &format!("Q{n}"), "A"))
+            .collect();
+        col.import_ai_cards(existing, deck.id, Default::default())
+            .unwrap();
+
+        let options = AIImportOptions {
+            on_duplicate: OnDuplicate::Skip,
+            ..Default::default()
+        };
+        let cards = vec![
+            approved_card("a", CardType::Basic, "<b>Q5</b>", "A"),
+            approved_card("b", CardType::Basic, "Q20", "A"),
+            // duplicates within the import are also caught
+            approved_card("c", CardType::Basic, "Q20", "A"),
+        ];
+        let response = col
+            .import_ai_cards(cards.clone(), deck.id, options.clone())
+            .unwrap();
+        assert_eq!(response.imported_count, 1);
+        assert_eq!(response.duplicate_count, 2);
+
+        // notes in other decks aren't considered
+        let response = col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(response.imported_count, 2);
+    }
+
+    #[test]
+    fn missing_requested_notetype_falls_back_to_default() {
+        let mut col = Collection::new();
+        let mut card = approved_card("1", CardType::Basic, "Q", "A");
+        card.notetype = Some("Missing".to_string());
+        let mut reversed = approved_card("2", CardType::Basic, "Q2", "A2");
+        reversed.notetype = Some(default_notetype_name(CardType::BasicReversed).to_string());
+
+        let response = col
+            .import_ai_cards(vec![card, reversed], DeckId(1), Default::default())
+            .unwrap();
+        assert_eq!(response.imported_count, 2);
+        assert_eq!(response.warnings.len(), 1);
+        assert!(response.warnings[0].contains("Missing"));
+        assert_eq!(response.generated_card_count, 3);
+    }
+
+    #[test]
+    fn missing_stock_notetypes_can_be_created() {
+        let mut col = Collection::new();
+        let cloze = col
+            .get_notetype_by_name(default_notetype_name(CardType::Cloze))
+            .unwrap()
+            .unwrap();
+        col.remove_notetype(cloze.id).unwrap();
+
+        let options = AIImportOptions {
+            create_missing_notetypes: true,
+            ..Default::default()
+        };
+        let cards = vec![approved_card("1", CardType::Cloze, "{{c1::Paris}}", "")];
+        let response = col
+            .import_ai_cards(cards, DeckId(1), options.clone())
+            .unwrap();
+        assert_eq!(response.imported_count, 1);
+        assert!(col.get_notetype_by_name("Cloze").unwrap().is_some());
+
+        // only stock notetypes are created
+        let mut custom = approved_card("2", CardType::Basic, "Q", "A");
+        custom.notetype = Some("Custom".to_string());
+        col.import_ai_cards(vec![custom], DeckId(1), options)
+            .unwrap();
+        assert!(col.get_notetype_by_name("Custom").unwrap().is_none());
+    }
+
+    #[test]
+    fn reversed_cards_can_degrade_to_basic() {
+        let mut col = Collection::new();
+        let reversed = col
+            .get_notetype_by_name(default_notetype_name(CardType::BasicReversed))
+            .unwrap()
+            .unwrap();
+        col.remove_notetype(reversed.id).unwrap();
+        let cards = vec![approved_card("1", CardType::BasicReversed, "Q", "A")];
+
+        assert!(col
+            .import_ai_cards(cards.clone(), DeckId(1), Default::default())
+            .is_err());
+
+        let options = AIImportOptions {
+            reversed_fallback: ReversedFallback::Degrade,
+            ..Default::default()
+        };
+        let response = col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(response.imported_count, 2);
+        let mut fields: Vec<Vec<String>> = col
+            .get_all_notes()
+            .into_iter()
+            .map(|note| note.fields().clone())
+            .collect();
+        fields.sort();
+        assert_eq!(fields, [["A", "Q"], ["Q", "A"]]);
+    }
+
+    #[test]
+    fn cards_can_be_imported_into_deck_named_after_source() {
+        let mut col = Collection::new();
+        let options = AIImportOptions {
+            create_deck_from_source: Some("/home/user/Cell::Biology.pdf".to_string()),
+            ..Default::default()
+        };
+        let cards = vec![approved_card("1", CardType::Basic, "Q", "A")];
+        let response = col
+            .import_ai_cards(cards.clone(), DeckId(1), options.clone())
+            .unwrap();
+        let deck_id = col.get_deck_id("Cell Biology").unwrap().unwrap();
+        assert_eq!(response.deck_id, deck_id.0);
+        let nid = col.get_all_notes()[0].id;
+        let cards_of_note = col.storage.all_cards_of_note(nid).unwrap();
+        assert_eq!(cards_of_note[0].deck_id, deck_id);
+
+        // the deck is reused by later imports
+        let response = col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(response.deck_id, deck_id.0);
+    }
+
+    #[test]
+    fn cloze_cards_can_be_capped() {
+        let text = (1..=30)
+            .map(|n| format!("{{{{c{n}::term{n}}}}}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let cards = vec![approved_card("1", CardType::Cloze, &text, "")];
+        let options = |cloze_overflow| AIImportOptions {
+            max_cloze_deletions: Some(10),
+            cloze_overflow,
+            ..Default::default()
+        };
+
+        let mut col = Collection::new();
+        let response = col
+            .import_ai_cards(cards.clone(), DeckId(1), options(ClozeOverflow::Reject))
+            .unwrap();
+        assert_eq!(response.imported_count, 0);
+        assert_eq!(response.errors.len(), 1);
+
+        let response = col
+            .import_ai_cards(cards.clone(), DeckId(1), options(ClozeOverflow::Split))
+            .unwrap();
+        assert_eq!(response.imported_count, 3);
+        assert_eq!(response.generated_card_count, 30);
+        assert_eq!(response.warnings.len(), 1);
+
+        let response = col
+            .import_ai_cards(cards, DeckId(1), options(ClozeOverflow::Import))
+            .unwrap();
+        assert_eq!(response.imported_count, 1);
+        assert_eq!(response.generated_card_count, 30);
+        assert_eq!(response.warnings.len(), 1);
+    }
+
+    #[test]
+    fn tagged_cards_can_be_suspended() {
+        let mut col = Collection::new();
+        let mut draft = approved_card("1", CardType::BasicReversed, "Draft", "A");
+        draft.suggested_tags = vec!["Draft".to_string()];
+        let cards = vec![draft, approved_card("2", CardType::Basic, "Final", "A")];
+        let options = AIImportOptions {
+            suspend_tags: vec!["draft".to_string()],
+            ..Default::default()
+        };
+        col.import_ai_cards(cards, DeckId(1), options).unwrap();
+
+        for note in col.get_all_notes() {
+            let suspended = note.fields()[0] == "Draft";
+            let cards = col.storage.all_cards_of_note(note.id).unwrap();
+            assert!(!cards.is_empty());
+            for card in cards {
+                assert_eq!(card.queue == CardQueue::Suspended, suspended);
+            }
+        }
+    }
+
+    #[test]
+    fn cards_can_start_with_an_interval() {
+        let mut col = Collection::new();
+        let cards = vec![approved_card("1", CardType::BasicReversed, "Q", "A")];
+        let options = AIImportOptions {
+            initial_interval: Some(10),
+            ..Default::default()
+        };
+        col.import_ai_cards(cards, DeckId(1), options).unwrap();
+
+        let today = col.timing_today().unwrap().days_elapsed as i32;
+        let nid = col.get_all_notes()[0].id;
+        let cards = col.storage.all_cards_of_note(nid).unwrap();
+        assert_eq!(cards.len(), 2);
+        for card in cards {
+            assert_eq!(card.ctype, crate::card::CardType::Review);
+            assert_eq!(card.interval, 10);
+            assert_eq!(card.due, today + 10);
+        }
+    }
+
+    #[test]
+    fn imported_notes_can_be_found_by_tag() {
+        let mut col = Collection::new();
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q1", "A1"),
+            approved_card("2", CardType::Basic, "Q2", "A2"),
+        ];
+        col.import_ai_cards(cards, DeckId(1), Default::default())
+            .unwrap();
+        let basic = col.get_notetype_by_name("Basic").unwrap().unwrap();
+        let mut other = basic.new_note();
+        other.set_field(0, "Manual").unwrap();
+        col.add_note(&mut other, DeckId(1)).unwrap();
+
+        let mut expected: Vec<NoteId> = col
+            .get_all_notes()
+            .into_iter()
+            .filter(|note| note.tags.iter().any(|t| t == AI_GENERATED_TAG))
+            .map(|note| note.id)
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(expected.len(), 2);
+        assert_eq!(
+            col.find_ai_generated_notes(AI_GENERATED_TAG).unwrap(),
+            expected
+        );
+        assert!(col.find_ai_generated_notes("other").unwrap().is_empty());
+    }
+
+    #[test]
+    fn notes_can_be_created_in_sorted_order() {
+        let mut col = Collection::new();
+        let cards = vec![
+            approved_card("1", CardType::Cloze, "{{c1::Paris}} is in France", ""),
+            approved_card("2", CardType::Basic, "b", "A"),
+            approved_card("3", CardType::Basic, "a", "A"),
+        ];
+        let options = AIImportOptions {
+            sort: SortKey::CardType,
+            ..Default::default()
+        };
+        col.import_ai_cards(cards, DeckId(1), options).unwrap();
+
+        let mut notes = col.get_all_notes();
+        notes.sort_by_key(|note| note.id);
+        let fronts: Vec<_> = notes.iter().map(|note| note.fields()[0].as_str()).collect();
+        assert_eq!(fronts, ["a", "b", "{{c1::Paris}} is in France"]);
+    }
+
+    #[test]
+    fn imported_sessions_are_finalized() {
+        let (mut col, _dir) = open_fs_test_collection("ai_import");
+        let cards = vec![approved_card("1", CardType::Basic, "Q", "A")];
+        col.save_ai_session(SaveSessionRequest {
+            cards: cards.clone(),
+            session_id: "session".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let options = |allow_finalized_session| AIImportOptions {
+            session_id: Some("session".to_string()),
+            allow_finalized_session,
+            ..Default::default()
+        };
+
+        col.import_ai_cards(cards.clone(), DeckId(1), options(false))
+            .unwrap();
+        assert!(col.load_ai_session(Default::default()).unwrap().finalized);
+
+        let err = col
+            .import_ai_cards(cards.clone(), DeckId(1), options(false))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AnkiError::AiFlashcardsError {
+                source: AiFlashcardsError::SessionFinalized
+            }
+        ));
+        col.import_ai_cards(cards, DeckId(1), options(true))
+            .unwrap();
+        assert_eq!(col.get_all_notes().len(), 2);
+    }
+
+    #[test]
+    fn footer_is_added_to_every_back() {
+        let mut col = Collection::new();
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q", "A"),
+            approved_card("2", CardType::Basic, "Q2", "A2"),
+            approved_card("3", CardType::Cloze, "{{c1::Paris}}", ""),
+        ];
+        let options = AIImportOptions {
+            back_footer_html: Some("<i>Source: notes</i><script>x()</script>".to_string()),
+            ..Default::default()
+        };
+        col.import_ai_cards(cards, DeckId(1), options).unwrap();
+
+        let mut backs: Vec<String> = col
+            .get_all_notes()
+            .iter()
+            .map(|note| note.fields()[1].clone())
+            .collect();
+        backs.sort();
+        assert_eq!(
+            backs,
+            [
+                "<i>Source: notes</i>",
+                "A2<br><i>Source: notes</i>",
+                "A<br><i>Source: notes</i>"
+            ]
+        );
+    }
+
+    #[test]
+    fn imported_cards_can_be_flagged() {
+        let mut col = Collection::new();
+        let cards = vec![approved_card("1", CardType::BasicReversed, "Q", "A")];
+        let options = AIImportOptions {
+            flag: Some(3),
+            ..Default::default()
+        };
+        col.import_ai_cards(cards, DeckId(1), options).unwrap();
+
+        let nid = col.get_all_notes()[0].id;
+        let cards = col.storage.all_cards_of_note(nid).unwrap();
+        assert_eq!(cards.len(), 2);
+        assert!(cards.iter().all(|card| card.flags == 3));
+
+        let options = AIImportOptions {
+            flag: Some(8),
+            ..Default::default()
+        };
+        let cards = vec![approved_card("2", CardType::Basic, "Q2", "A2")];
+        assert!(col.import_ai_cards(cards, DeckId(1), options).is_err());
+    }
+
+    #[test]
+    fn cards_emptied_by_sanitizing_can_be_skipped() {
+        let mut col = Collection::new();
+        let cards = vec![
+            approved_card("1", CardType::Basic, "Q", "A"),
+            approved_card("2", CardType::Basic, "<script>x()</script>", "A2"),
+            approved_card("3", CardType::Basic, "Q3", "A3"),
+        ];
+        let options = AIImportOptions {
+            skip_emptied_cards: true,
+            ..Default::default()
+        };
+        let response = col.import_ai_cards(cards, DeckId(1), options).unwrap();
+
+        assert_eq!(response.imported_count, 2);
+        assert_eq!(response.errors.len(), 1);
+        assert!(response.errors[0].contains("Card '2'"));
+        assert!(response.errors[0].contains("front"));
+        assert_eq!(col.get_all_notes().len(), 2);
+
+        // Markdown fields are escaped rather than emptied
+        let cards = vec![approved_card(
+            "4",
+            CardType::Basic,
+            "<script>x()</script>",
+            "A4",
+        )];
+        let options = AIImportOptions {
+            skip_emptied_cards: true,
+            validation: ValidationConfig {
+                treat_as_markdown: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let response = col.import_ai_cards(cards, DeckId(1), options).unwrap();
+        assert_eq!(response.imported_count, 1);
+        assert!(response.errors.is_empty());
+    }
+}
@@ -0,0 +1,1516 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Persistence of the review session, so users can resume reviewing
+//! generated cards later.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+use anki_io::atomic_rename;
+use anki_io::new_tempfile_in_parent_of;
+use anki_io::write_file;
+use anki_proto::ai_flashcards::CardStatus;
+use anki_proto::ai_flashcards::CardType;
+use anki_proto::ai_flashcards::CardValidation;
+use anki_proto::ai_flashcards::GeneratedCard;
+use anki_proto::ai_flashcards::LoadSessionRequest;
+use anki_proto::ai_flashcards::LoadSessionResponse;
+use anki_proto::ai_flashcards::SaveSessionRequest;
+use anki_proto::ai_flashcards::SaveSessionResponse;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::card_type_from_name;
+use super::card_type_name;
+use super::encryption;
+use super::sanitize::sanitize_tag;
+use super::sanitize::TagSanitizeConfig;
+use super::validate::validate_card;
+use super::validate::IssueSeverity;
+use super::validate::ValidationConfig;
+use super::validate::ValidationResult;
+use super::AIGeneratedCard;
+use super::AiFlashcardsError;
+use crate::error;
+use crate::prelude::*;
+
+/// Session file format version for compatibility checking
+const SESSION_VERSION: u32 = 1;
+
+/// Maximum session age in seconds (7 days)
+const SESSION_MAX_AGE_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Session file name
+const SESSION_FILENAME: &str = "ai_flashcards_session.json";
+
+/// Locks for session files, keyed by path, so that reads and writes from
+/// different collection instances in this process can't interleave.
+static SESSION_LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+/// Serializable session format for JSON persistence
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionFile {
+    version: u32,
+    /// Empty in sessions saved before this was tracked
+    #[serde(default)]
+    id: String,
+    created_timestamp: i64,
+    source_name: String,
+    source_text: String,
+    cards: Vec<SessionCard>,
+    /// Set once the session's cards have been imported, so the user can be
+    /// warned before importing them again.
+    #[serde(default)]
+    finalized: bool,
+    /// The config the session was last revalidated with, if any. Cards that
+    /// change are validated with it when the session is saved again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    validation_config: Option<ValidationConfig>,
+}
+
+/// The start of a session file, read without the cards to quickly check
+/// whether the session is usable.
+#[derive(Deserialize)]
+struct SessionHeader {
+    version: u32,
+    created_timestamp: i64,
+}
+
+/// False if a session with this version and creation time is incompatible
+/// or expired, and should be discarded.
+fn is_current_session(version: u32, created_timestamp: i64) -> bool {
+    version == SESSION_VERSION && TimestampSecs::now().0 - created_timestamp <= SESSION_MAX_AGE_SECS
+}
+
+/// The version of the session file at `path`, for deciding at startup whether
+/// it needs migrating. Only the version is deserialized, so this is cheap
+/// even for large sessions. Returns `None` if the file is missing, unreadable
+/// or encrypted.
+pub fn session_version(path: &Path) -> Option<u32> {
+    #[derive(Deserialize)]
+    struct VersionOnly {
+        version: u32,
+    }
+
+    let data = fs::read(path).ok()?;
+    if encryption::is_encrypted(&data) {
+        return None;
+    }
+    serde_json::from_slice::<VersionOnly>(&data)
+        .ok()
+        .map(|file| file.version)
+}
+
+/// Card format for JSON persistence
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionCard {
+    id: String,
+    card_type: String,
+    front: String,
+    back: String,
+    suggested_tags: Vec<String>,
+    status: String,
+    /// Why the user rejected the card, if they gave a reason
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reject_reason: Option<String>,
+    /// When the card was first added to the session; 0 in sessions saved
+    /// before this was tracked
+    #[serde(default)]
+    created_timestamp: i64,
+    /// Validation outcome as of the last save; absent in older sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    validation: Option<StoredValidation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    confidence: Option<f32>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notetype: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    examples: Vec<String>,
+    /// Messages of warnings the user dismissed, which are left out when the
+    /// card is validated again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    suppressed_warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct StoredValidation {
+    valid: bool,
+    issues: Vec<String>,
+}
+
+impl From<StoredValidation> for CardValidation {
+    fn from(validation: StoredValidation) -> Self {
+        CardValidation {
+            valid: validation.valid,
+            issues: validation.issues,
+        }
+    }
+}
+
+impl StoredValidation {
+    /// Summarize the result, leaving out the dismissed warnings. Errors can't
+    /// be dismissed, as they would prevent the card from being imported.
+    fn new(result: &ValidationResult, suppressed: &[String]) -> Self {
+        StoredValidation {
+            valid: result.valid,
+            issues: result
+                .issues
+                .iter()
+                .filter(|i| i.severity == IssueSeverity::Error || !suppressed.contains(&i.message))
+                .map(|i| i.message.clone())
+                .collect(),
+        }
+    }
+}
+
+impl SessionCard {
+    /// Convert a card for storage. The creation time of `previous`, the
+    /// stored card with the same id, is kept. If the card is unchanged from
+    /// it, the stored validation summary is kept too; otherwise the card is
+    /// validated again with `config`, as it may have been edited.
+    fn new(
+        card: &GeneratedCard,
+        previous: Option<&SessionCard>,
+        config: &ValidationConfig,
+    ) -> Self {
+        let mut stored = SessionCard {
+            id: card.id.clone(),
+            card_type: card_type_name(card.card_type()).to_string(),
+            front: card.front.clone(),
+            back: card.back.clone(),
+            suggested_tags: card.suggested_tags.clone(),
+            status: match card.status() {
+                CardStatus::Pending => "pending".to_string(),
+                CardStatus::Approved => "approved".to_string(),
+                CardStatus::Rejected => "rejected".to_string(),
+            },
+            reject_reason: card.reject_reason.clone(),
+            created_timestamp: match previous {
+                Some(previous) if previous.created_timestamp > 0 => previous.created_timestamp,
+                _ if card.created_timestamp > 0 => card.created_timestamp,
+                _ => TimestampSecs::now().0,
+            },
+            validation: None,
+            confidence: card.confidence,
+            reference: card.reference.clone(),
+            notetype: card.notetype.clone(),
+            notes: card.notes.clone(),
+            examples: card.examples.clone(),
+            suppressed_warnings: card.suppressed_warnings.clone(),
+        };
+        let unchanged = previous
+            .filter(|previous| {
+                previous.same_content(&stored)
+                    && previous.suppressed_warnings == stored.suppressed_warnings
+            })
+            .and_then(|previous| previous.validation.clone());
+        stored.validation = Some(unchanged.unwrap_or_else(|| {
+            let result = validate_card(&card.clone().into(), config);
+            StoredValidation::new(&result, &card.suppressed_warnings)
+        }));
+        stored
+    }
+}
+
+impl From<SessionCard> for GeneratedCard {
+    fn from(card: SessionCard) -> Self {
+        GeneratedCard {
+            id: card.id,
+            card_type: card_type_from_name(&card.card_type)
+                .unwrap_or(CardType::Basic)
+                .into(),
+            front: card.front,
+            back: card.back,
+            suggested_tags: card.suggested_tags,
+            status: match card.status.as_str() {
+                "pending" => CardStatus::Pending.into(),
+                "approved" => CardStatus::Approved.into(),
+                "rejected" => CardStatus::Rejected.into(),
+                _ => CardStatus::Pending.into(),
+            },
+            created_timestamp: card.created_timestamp,
+            reject_reason: card.reject_reason,
+            validation: card.validation.map(Into::into),
+            confidence: card.confidence,
+            reference: card.reference,
+            notetype: card.notetype,
+            notes: card.notes,
+            examples: card.examples,
+            suppressed_warnings: card.suppressed_warnings,
+        }
+    }
+}
+
+impl SessionFile {
+    pub(super) fn into_cards(self) -> Vec<SessionCard> {
+        self.cards
+    }
+}
+
+impl SessionCard {
+    fn same_content(&self, other: &SessionCard) -> bool {
+        self.card_type == other.card_type
+            && self.front == other.front
+            && self.back == other.back
+            && self.suggested_tags == other.suggested_tags
+    }
+}
+
+/// Card ids that differ between two sessions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionDiff {
+    /// Ids of cards only present in the new session
+    pub added: Vec<String>,
+    /// Ids of cards only present in the old session
+    pub removed: Vec<String>,
+    /// Ids (in the new session) of cards whose content changed
+    pub modified: Vec<String>,
+}
+
+/// Compare two sessions, eg before and after regenerating cards. Cards are
+/// matched by id, and cards whose id changed are matched by their front.
+pub fn diff_sessions(old: &SessionFile, new: &SessionFile) -> SessionDiff {
+    let mut diff = SessionDiff::default();
+    let mut unmatched_old: Vec<&SessionCard> = old
+        .cards
+        .iter()
+        .filter(|card| !new.cards.iter().any(|other| other.id == card.id))
+        .collect();
+    for card in &new.cards {
+        let previous = match old.cards.iter().find(|other| other.id == card.id) {
+            Some(previous) => Some(previous),
+            None => unmatched_old
+                .iter()
+                .position(|other| other.front == card.front)
+                .map(|idx| unmatched_old.remove(idx)),
+        };
+        match previous {
+            Some(previous) if !previous.same_content(card) => diff.modified.push(card.id.clone()),
+            Some(_) => {}
+            None => diff.added.push(card.id.clone()),
+        }
+    }
+    diff.removed = unmatched_old.iter().map(|card| card.id.clone()).collect();
+    diff
+}
+
+/// Generate a random id for a card added to a session, formatted like a UUID.
+pub fn new_session_card_id() -> String {
+    let hex = format!("{:032x}", rand::random::<u128>());
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The result of reading the session file from disk.
+enum StoredSession {
+    /// No session file, or the session was expired or incompatible
+    Missing,
+    /// The session is encrypted, and the passphrase didn't match
+    Locked,
+    Loaded(SessionFile),
+}
+
+impl Collection {
+    /// Get the session file path for this collection. The session is stored
+    /// next to the collection file, and never in the current directory or
+    /// the root of the filesystem, as those may be shared with other
+    /// collections.
+    fn ai_session_path(&self) -> error::Result<PathBuf> {
+        // the current directory is an empty path, and the root has no parent
+        let dir = self
+            .col_path
+            .parent()
+            .filter(|dir| dir.parent().is_some())
+            .ok_or(AiFlashcardsError::SessionUnavailable)?;
+        Ok(dir.join(SESSION_FILENAME))
+    }
+
+    /// Save AI session to disk
+    pub fn save_ai_session(
+        &self,
+        request: SaveSessionRequest,
+    ) -> error::Result<SaveSessionResponse> {
+        // Users may opt out of keeping the (possibly sensitive) source on disk
+        let persist_source_text = request.persist_source_text.unwrap_or(true);
+        let mut warnings = vec![];
+        if !request.cards.is_empty() {
+            if request.source_name.trim().is_empty() {
+                warnings.push("The session has no source name".to_string());
+            }
+            if persist_source_text && request.source_text.trim().is_empty() {
+                warnings.push(
+                    "The session has no source text, so its cards can't be regenerated".to_string(),
+                );
+            }
+        }
+        if request.require_source && !warnings.is_empty() {
+            return Err(AiFlashcardsError::ValidationFailed {
+                info: warnings.join("; "),
+            }
+            .into());
+        }
+        let source_text = if persist_source_text {
+            request.source_text
+        } else {
+            String::new()
+        };
+
+        self.with_ai_session_lock(|| {
+            let existing = match self.read_ai_session_file(&request.passphrase) {
+                Ok(StoredSession::Loaded(existing)) => Some(existing),
+                Ok(StoredSession::Missing) => None,
+                // Only those who can open an encrypted session may replace it,
+                // so that its finalized state can't be bypassed
+                Ok(StoredSession::Locked) => return Err(AiFlashcardsError::SessionLocked.into()),
+                // a damaged file is replaced
+                Err(AnkiError::AiFlashcardsError {
+                    source: AiFlashcardsError::SessionCorrupt { .. },
+                }) => None,
+                Err(err) => return Err(err),
+            };
+            // Saving without an id continues the stored session, and gives it
+            // an id if it was saved before they were tracked
+            let continued = request.session_id.is_empty();
+            let id = if !continued {
+                request.session_id
+            } else {
+                existing
+                    .as_ref()
+                    .map(|existing| existing.id.clone())
+                    .filter(|id| !id.is_empty())
+                    .unwrap_or_else(new_session_card_id)
+            };
+            let existing = existing
+                .filter(|existing| existing.id == id || (existing.id.is_empty() && continued));
+            let previous_cards: HashMap<&str, &SessionCard> = existing
+                .iter()
+                .flat_map(|existing| &existing.cards)
+                .map(|card| (card.id.as_str(), card))
+                .collect();
+            let validation_config = existing
+                .as_ref()
+                .and_then(|existing| existing.validation_config.clone());
+            let config = validation_config.clone().unwrap_or_default();
+            let session = SessionFile {
+                version: SESSION_VERSION,
+                // Saving the same session again shouldn't extend its lifetime
+                created_timestamp: existing
+                    .as_ref()
+                    .map(|existing| existing.created_timestamp)
+                    .unwrap_or_else(|| TimestampSecs::now().0),
+                id,
+                source_name: request.source_name,
+                source_text,
+                cards: request
+                    .cards
+                    .iter()
+                    .map(|card| {
+                        SessionCard::new(
+                            card,
+                            previous_cards.get(card.id.as_str()).copied(),
+                            &config,
+                        )
+                    })
+                    .collect(),
+                // an imported session stays imported
+                finalized: request.finalized
+                    || existing.as_ref().is_some_and(|existing| existing.finalized),
+                validation_config,
+            };
+            self.write_ai_session_file(&session, &request.passphrase)
+        })?;
+        Ok(SaveSessionResponse { warnings })
+    }
+
+    /// Load AI session from disk
+    pub fn load_ai_session(
+        &self,
+        request: LoadSessionRequest,
+    ) -> error::Result<LoadSessionResponse> {
+        let stored =
+            self.with_ai_session_lock(|| self.read_ai_session_file(&request.passphrase))?;
+        Ok(load_session_response(stored))
+    }
+
+    /// Load a session from the provided file, such as one shared by another
+    /// user. Unlike the stored session, an expired or incompatible file is
+    /// reported as missing but left in place, as it doesn't belong to us.
+    pub fn load_ai_session_from(
+        &self,
+        path: PathBuf,
+        passphrase: &str,
+    ) -> error::Result<LoadSessionResponse> {
+        let stored = with_session_lock(&path, || read_session_file_at(&path, passphrase))?;
+        Ok(load_session_response(stored))
+    }
+    /// True if the stored session has the provided id, and its cards have
+    /// already been imported. Encrypted sessions can't be checked without
+    /// their passphrase, so they are assumed not to be finalized, as are
+    /// sessions of collections without a folder to store them in.
+    pub(crate) fn ai_session_finalized(&self, session_id: &str) -> error::Result<bool> {
+        if self.ai_session_path().is_err() {
+            return Ok(false);
+        }
+        Ok(
+            match self.with_ai_session_lock(|| self.read_ai_session_file(""))? {
+                StoredSession::Loaded(session) => session.id == session_id && session.finalized,
+                StoredSession::Missing | StoredSession::Locked => false,
+            },
+        )
+    }
+
+    /// Mark the stored session as imported, if it has the provided id and
+    /// isn't encrypted.
+    pub(crate) fn finalize_ai_session(&self, session_id: &str) -> error::Result<()> {
+        if self.ai_session_path().is_err() {
+            return Ok(());
+        }
+        self.with_ai_session_lock(|| {
+            if let StoredSession::Loaded(mut session) = self.read_ai_session_file("")? {
+                if session.id == session_id && !session.finalized {
+                    session.finalized = true;
+                    self.write_ai_session_file(&session, "")?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// True if a session is stored that [Collection::load_ai_session] would
+    /// return. This is cheaper than loading it, as the cards are not
+    /// deserialized. Encrypted sessions can't be checked without their
+    /// passphrase, so they are assumed to be usable.
+    pub fn has_ai_session(&self) -> bool {
+        let Ok(data) = self.ai_session_path().and_then(|path| Ok(fs::read(path)?)) else {
+            return false;
+        };
+        encryption::is_encrypted(&data)
+            || serde_json::from_slice::<SessionHeader>(&data)
+                .is_ok_and(|header| is_current_session(header.version, header.created_timestamp))
+    }
+
+    /// Read the stored session, so it can later be compared with
+    /// [diff_sessions]. Returns `None` if there is no session, or the
+    /// passphrase doesn't match.
+    pub fn ai_session_snapshot(&self, passphrase: &str) -> error::Result<Option<SessionFile>> {
+        Ok(
+            match self.with_ai_session_lock(|| self.read_ai_session_file(passphrase))? {
+                StoredSession::Loaded(session) => Some(session),
+                StoredSession::Missing | StoredSession::Locked => None,
+            },
+        )
+    }
+
+    /// Add cards to the end of the stored session, giving any card without
+    /// an id a new one. Returns the ids of the added cards.
+    pub fn append_ai_session_cards(
+        &self,
+        cards: Vec<AIGeneratedCard>,
+    ) -> error::Result<Vec<String>> {
+        self.update_ai_session(|session| {
+            let config = session.validation_config.clone().unwrap_or_default();
+            let mut ids = Vec::with_capacity(cards.len());
+            for mut card in cards {
+                if card.id.is_empty() {
+                    card.id = new_session_card_id();
+                }
+                ids.push(card.id.clone());
+                session
+                    .cards
+                    .push(SessionCard::new(&GeneratedCard::from(card), None, &config));
+            }
+            Ok(ids)
+        })
+    }
+
+    /// Mark a card in the stored session as rejected, recording the reason
+    /// if one was given.
+    pub fn reject_ai_session_card(&self, id: &str, reason: Option<String>) -> error::Result<()> {
+        self.update_ai_session(|session| {
+            let card = session
+                .cards
+                .iter_mut()
+                .find(|card| card.id == id)
+                .or_not_found(id)?;
+            card.status = "rejected".to_string();
+            card.reject_reason = reason;
+            Ok(())
+        })
+    }
+
+    /// Add a tag to every card in the stored session that doesn't already
+    /// have it. The tag is sanitized first, and an error is returned if
+    /// nothing usable remains. Returns the number of changed cards.
+    pub fn add_tag_to_session(&self, tag: &str) -> error::Result<usize> {
+        let tag = sanitize_tag(tag, &TagSanitizeConfig::default())
+            .tag
+            .or_invalid("tag has no usable characters")?;
+        self.update_ai_session(|session| {
+            let mut changed = 0;
+            for card in &mut session.cards {
+                if !card
+                    .suggested_tags
+                    .iter()
+                    .any(|t| t.eq_ignore_ascii_case(&tag))
+                {
+                    card.suggested_tags.push(tag.clone());
+                    changed += 1;
+                }
+            }
+            Ok(changed)
+        })
+    }
+
+    /// Validate every card in the stored session again, such as after the
+    /// validation rules changed, storing the sanitized content and the new
+    /// validation summaries. Returns the number of cards that are now invalid.
+    pub fn revalidate_session(&self, config: &ValidationConfig) -> error::Result<usize> {
+        self.update_ai_session(|session| {
+            let mut invalid = 0;
+            for card in &mut session.cards {
+                let result = validate_card(&GeneratedCard::from(card.clone()).into(), config);
+                card.card_type = card_type_name(result.card.card_type).to_string();
+                card.front.clone_from(&result.card.front);
+                card.back.clone_from(&result.card.back);
+                card.suggested_tags.clone_from(&result.card.suggested_tags);
+                card.validation = Some(StoredValidation::new(&result, &card.suppressed_warnings));
+                if !result.valid {
+                    invalid += 1;
+                }
+            }
+            session.validation_config = Some(config.clone());
+            Ok(invalid)
+        })
+    }
+
+    /// Remove a tag from every card in the stored session, ignoring case.
+    /// Returns the number of changed cards.
+    pub fn remove_tag_from_session(&self, tag: &str) -> error::Result<usize> {
+        let Some(tag) = sanitize_tag(tag, &TagSanitizeConfig::default()).tag else {
+            return Ok(0);
+        };
+        self.update_ai_session(|session| {
+            let mut changed = 0;
+            for card in &mut session.cards {
+                let before = card.suggested_tags.len();
+                card.suggested_tags
+                    .retain(|t| !t.eq_ignore_ascii_case(&tag));
+                if card.suggested_tags.len() != before {
+                    changed += 1;
+                }
+            }
+            Ok(changed)
+        })
+    }
+
+    /// Count the rejected cards in the stored session by rejection reason.
+    /// Cards rejected without a reason are counted under an empty string.
+    pub fn session_rejection_stats(&self) -> error::Result<HashMap<String, u32>> {
+        let mut stats = HashMap::new();
+        if let Some(session) = self.with_ai_session_lock(|| self.plain_ai_session())? {
+            for card in session
+                .cards
+                .iter()
+                .filter(|card| card.status == "rejected")
+            {
+                let reason = card.reject_reason.as_deref().unwrap_or_default().trim();
+                *stats.entry(reason.to_string()).or_default() += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Clear AI session from disk
+    pub fn clear_ai_session(&self) -> error::Result<()> {
+        let path = self.ai_session_path()?;
+        self.with_ai_session_lock(|| {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Run `op` while holding the lock for this collection's session file.
+    /// See [with_session_lock].
+    fn with_ai_session_lock<T>(&self, op: impl FnOnce() -> error::Result<T>) -> error::Result<T> {
+        with_session_lock(&self.ai_session_path()?, op)
+    }
+
+    /// Apply `op` to the stored session, and save the result. The helpers
+    /// using this don't take a passphrase, so they fail with
+    /// [AiFlashcardsError::SessionLocked] if the session is encrypted.
+    fn update_ai_session<T>(
+        &self,
+        op: impl FnOnce(&mut SessionFile) -> error::Result<T>,
+    ) -> error::Result<T> {
+        self.with_ai_session_lock(|| {
+            let mut session = self
+                .plain_ai_session()?
+                .ok_or(AiFlashcardsError::SessionMissing)?;
+            let output = op(&mut session)?;
+            self.write_ai_session_file(&session, "")?;
+            Ok(output)
+        })
+    }
+
+    /// Read the stored session, if one exists. Encrypted sessions can't be
+    /// read without their passphrase, so they produce an error.
+    fn plain_ai_session(&self) -> error::Result<Option<SessionFile>> {
+        match self.read_ai_session_file("")? {
+            StoredSession::Loaded(session) => Ok(Some(session)),
+            StoredSession::Missing => Ok(None),
+            StoredSession::Locked => Err(AiFlashcardsError::SessionLocked.into()),
+        }
+    }
+
+    /// Read the session file, discarding it if it is expired or incompatible.
+    fn read_ai_session_file(&self, passphrase: &str) -> error::Result<StoredSession> {
+        let path = self.ai_session_path()?;
+        let stored = read_session_file_at(&path, passphrase)?;
+        // A file that exists but produced no session is expired or incompatible
+        if matches!(stored, StoredSession::Missing) && path.exists() {
+            let _ = fs::remove_file(&path);
+        }
+        Ok(stored)
+    }
+
+    /// Write the session file, encrypting it if a passphrase is provided.
+    fn write_ai_session_file(&self, session: &SessionFile, passphrase: &str) -> error::Result<()> {
+        write_session_file_at(&self.ai_session_path()?, session, passphrase)
+    }
+}
+
+/// Run `op` while holding the lock for the session file at `path`, so that
+/// reads and changes from different collection instances in this process
+/// are applied one at a time. The lock is not reentrant.
+fn with_session_lock<T>(path: &Path, op: impl FnOnce() -> error::Result<T>) -> error::Result<T> {
+    let lock = SESSION_LOCKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(path.to_owned())
+        .or_default()
+        .clone();
+    let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+    op()
+}
+
+/// Write a session file, encrypting it if a passphrase is provided. The
+/// content is written to a temporary file that then replaces the original,
+/// so readers never see a partly-written session.
+fn write_session_file_at(
+    path: &Path,
+    session: &SessionFile,
+    passphrase: &str,
+) -> error::Result<()> {
+    let json = serde_json::to_string_pretty(session)?;
+    let data = if passphrase.is_empty() {
+        json.into_bytes()
+    } else {
+        encryption::encrypt(json.as_bytes(), passphrase)?
+    };
+    let temp = new_tempfile_in_parent_of(path)?;
+    write_file(temp.path(), data)?;
+    atomic_rename(temp, path, false)?;
+    Ok(())
+}
+
+/// Build the response for a session read from disk.
+fn load_session_response(stored: StoredSession) -> LoadSessionResponse {
+    let session = match stored {
+        StoredSession::Missing => return LoadSessionResponse::default(),
+        StoredSession::Locked => {
+            return LoadSessionResponse {
+                locked: true,
+                ..Default::default()
+            }
+        }
+        StoredSession::Loaded(session) => session,
+    };
+
+    LoadSessionResponse {
+        has_session: true,
+        cards: session.cards.into_iter().map(Into::into).collect(),
+        source_name: session.source_name,
+        created_timestamp: session.created_timestamp,
+        source_text: session.source_text,
+        locked: false,
+        session_id: session.id,
+        finalized: session.finalized,
+    }
+}
+
+/// Read a session file. Expired or incompatible sessions are reported as
+/// missing; the file itself is left untouched.
+fn read_session_file_at(path: &Path, passphrase: &str) -> error::Result<StoredSession> {
+    if !path.exists() {
+        return Ok(StoredSession::Missing);
+    }
+
+    let mut json = fs::read(path)?;
+    if encryption::is_encrypted(&json) {
+        match encryption::decrypt(&json, passphrase)? {
+            Some(decrypted) => json = decrypted,
+            // Leave the file in place, so it can be opened with the
+            // correct passphrase later
+            None => return Ok(StoredSession::Locked),
+        }
+    }
+    let mut session: SessionFile =
+        serde_json::from_slice(&json).map_err(|err| AiFlashcardsError::SessionCorrupt {
+            info: err.to_string(),
+        })?;
+
+    // Ignore incompatible or expired sessions
+    if !is_current_session(session.version, session.created_timestamp) {
+        return Ok(StoredSession::Missing);
+    }
+
+    // Cards from older sessions take the session's timestamp
+    for card in &mut session.cards {
+        if card.created_timestamp == 0 {
+            card.created_timestamp = session.created_timestamp;
+        }
+    }
+
+    Ok(StoredSession::Loaded(session))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::ai_flashcards::parse::parse_openai_response;
+    use crate::ai_flashcards::test::approved_card;
+
+    #[test]
+    fn source_text_can_be_kept_out_of_session_file() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let request = SaveSessionRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+            source_name: "notes.pdf".to_string(),
+            source_text: "sensitive".to_string(),
+            persist_source_text: None,
+            ..Default::default()
+        };
+        col.save_ai_session(request.clone()).unwrap();
+        assert_eq!(
+            col.load_ai_session(Default::default()).unwrap().source_text,
+            "sensitive"
+        );
+
+        col.save_ai_session(SaveSessionRequest {
+            persist_source_text: Some(false),
+            ..request
+        })
+        .unwrap();
+        let session = col.load_ai_session(Default::default()).unwrap();
+        assert!(session.has_session);
+        assert_eq!(session.source_text, "");
+        assert_eq!(session.cards.len(), 1);
+        let json = fs::read_to_string(col.ai_session_path().unwrap()).unwrap();
+        assert!(!json.contains("sensitive"));
+    }
+
+    #[test]
+    fn empty_source_produces_warnings() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let request = SaveSessionRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+            ..Default::default()
+        };
+        let response = col.save_ai_session(request.clone()).unwrap();
+        assert_eq!(response.warnings.len(), 2);
+        assert!(col.load_ai_session(Default::default()).unwrap().has_session);
+
+        // text that was deliberately not persisted isn't reported
+        let response = col
+            .save_ai_session(SaveSessionRequest {
+                source_name: "notes.pdf".to_string(),
+                persist_source_text: Some(false),
+                ..request.clone()
+            })
+            .unwrap();
+        assert!(response.warnings.is_empty());
+
+        col.clear_ai_session().unwrap();
+        let err = col
+            .save_ai_session(SaveSessionRequest {
+                require_source: true,
+                ..request
+            })
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AnkiError::AiFlashcardsError {
+                source: AiFlashcardsError::ValidationFailed { .. }
+            }
+        ));
+        assert!(!col.load_ai_session(Default::default()).unwrap().has_session);
+    }
+
+    #[test]
+    fn session_can_be_encrypted() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+            source_name: "notes.pdf".to_string(),
+            source_text: "sensitive".to_string(),
+            passphrase: "secret".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let data = fs::read(col.ai_session_path().unwrap()).unwrap();
+        assert!(encryption::is_encrypted(&data));
+
+        let session = col
+            .load_ai_session(LoadSessionRequest {
+                passphrase: "secret".to_string(),
+            })
+            .unwrap();
+        assert!(session.has_session);
+        assert_eq!(session.source_text, "sensitive");
+        assert_eq!(session.cards[0].front, "Q");
+
+        // a wrong passphrase reports a locked session, and leaves the file alone
+        let session = col.load_ai_session(Default::default()).unwrap();
+        assert!(!session.has_session);
+        assert!(session.locked);
+        assert!(col.ai_session_path().unwrap().exists());
+    }
+
+    #[test]
+    fn encrypted_sessions_can_only_be_replaced_with_their_passphrase() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let request = SaveSessionRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+            session_id: "session".to_string(),
+            passphrase: "secret".to_string(),
+            finalized: true,
+            ..Default::default()
+        };
+        col.save_ai_session(request.clone()).unwrap();
+
+        let err = col
+            .save_ai_session(SaveSessionRequest {
+                passphrase: String::new(),
+                finalized: false,
+                ..request.clone()
+            })
+            .unwrap_err();
+        assert_eq!(
+            err,
+            AnkiError::AiFlashcardsError {
+                source: AiFlashcardsError::SessionLocked
+            }
+        );
+
+        // with the passphrase, the session is saved but stays finalized
+        col.save_ai_session(SaveSessionRequest {
+            finalized: false,
+            ..request
+        })
+        .unwrap();
+        let loaded = col
+            .load_ai_session(LoadSessionRequest {
+                passphrase: "secret".to_string(),
+            })
+            .unwrap();
+        assert!(loaded.finalized);
+    }
+
+    #[test]
+    fn card_timestamps_survive_save_and_load() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let mut old_card = approved_card("1", CardType::Basic, "Q", "A");
+        old_card.created_timestamp = 1_000;
+        let new_card = approved_card("2", CardType::Basic, "Q2", "A2");
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![old_card, new_card],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let cards = col.load_ai_session(Default::default()).unwrap().cards;
+        assert_eq!(cards[0].created_timestamp, 1_000);
+        assert!(cards[1].created_timestamp >= TimestampSecs::now().0 - 60);
+        // timestamps are kept when the loaded cards are saved again
+        col.save_ai_session(SaveSessionRequest {
+            cards: cards.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            col.load_ai_session(Default::default()).unwrap().cards,
+            cards
+        );
+        // and when they are saved without one, as the frontend does
+        let without_timestamps = cards
+            .iter()
+            .cloned()
+            .map(|card| GeneratedCard {
+                created_timestamp: 0,
+                ..card
+            })
+            .collect();
+        col.save_ai_session(SaveSessionRequest {
+            cards: without_timestamps,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            col.load_ai_session(Default::default()).unwrap().cards,
+            cards
+        );
+    }
+
+    #[test]
+    fn cards_from_older_sessions_take_session_timestamp() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let created = TimestampSecs::now().0 - 100;
+        let json = format!(
+            r#"{{"version": 1, "created_timestamp": {created}, "source_name": "", "source_text": "",
+                "cards": [{{"id": "1", "card_type": "basic", "front": "Q", "back": "A",
+                "suggested_tags": [], "status": "pending"}}]}}"#
+        );
+        fs::write(col.ai_session_path().unwrap(), json).unwrap();
+        let cards = col.load_ai_session(Default::default()).unwrap().cards;
+        assert_eq!(cards[0].created_timestamp, created);
+    }
+
+    #[test]
+    fn session_can_be_loaded_from_another_path() {
+        let (col, dir) = open_fs_test_collection("ai_session");
+        let cards = vec![approved_card("1", CardType::Basic, "Q", "A")];
+        col.save_ai_session(SaveSessionRequest {
+            cards: cards.clone(),
+            source_name: "shared.pdf".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let shared = dir.path().join("shared_session.json");
+        fs::rename(col.ai_session_path().unwrap(), &shared).unwrap();
+        assert!(!col.load_ai_session(Default::default()).unwrap().has_session);
+
+        let loaded = col.load_ai_session_from(shared.clone(), "").unwrap();
+        assert!(loaded.has_session);
+        assert_eq!(loaded.source_name, "shared.pdf");
+        assert_eq!(loaded.cards, cards);
+
+        // expired files are ignored, but not removed, as they aren't ours
+        let expired = r#"{"version": 1, "created_timestamp": 0, "source_name": "",
+            "source_text": "", "cards": []}"#;
+        fs::write(&shared, expired).unwrap();
+        assert!(
+            !col.load_ai_session_from(shared.clone(), "")
+                .unwrap()
+                .has_session
+        );
+        assert!(shared.exists());
+    }
+
+    #[test]
+    fn saving_a_session_again_keeps_its_creation_time() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let path = col.ai_session_path().unwrap();
+        let created = TimestampSecs::now().0 - 1_000;
+        fs::write(
+            &path,
+            format!(
+                r#"{{"version": {SESSION_VERSION}, "id": "abc", "created_timestamp": {created},
+                "source_name": "", "source_text": "", "cards": []}}"#
+            ),
+        )
+        .unwrap();
+
+        let request = SaveSessionRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+            session_id: "abc".to_string(),
+            ..Default::default()
+        };
+        col.save_ai_session(request.clone()).unwrap();
+        let loaded = col.load_ai_session(Default::default()).unwrap();
+        assert_eq!(loaded.created_timestamp, created);
+
+        // saving without an id continues the stored session
+        col.save_ai_session(SaveSessionRequest {
+            session_id: String::new(),
+            ..request.clone()
+        })
+        .unwrap();
+        let loaded = col.load_ai_session(Default::default()).unwrap();
+        assert_eq!(loaded.session_id, "abc");
+        assert_eq!(loaded.created_timestamp, created);
+
+        // a different session starts afresh
+        col.save_ai_session(SaveSessionRequest {
+            session_id: "def".to_string(),
+            ..request
+        })
+        .unwrap();
+        let loaded = col.load_ai_session(Default::default()).unwrap();
+        assert!(loaded.created_timestamp > created);
+    }
+
+    #[test]
+    fn session_presence_can_be_checked_cheaply() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        assert!(!col.has_ai_session());
+
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(col.has_ai_session());
+
+        let expired = r#"{"version": 1, "created_timestamp": 0, "source_name": "",
+            "source_text": "", "cards": []}"#;
+        fs::write(col.ai_session_path().unwrap(), expired).unwrap();
+        assert!(!col.has_ai_session());
+    }
+
+    #[test]
+    fn rejection_reason_survives_save_and_load() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![
+                approved_card("1", CardType::Basic, "Q", "A"),
+                approved_card("2", CardType::Basic, "Q2", "A2"),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        col.reject_ai_session_card("2", Some("too vague".to_string()))
+            .unwrap();
+        assert!(col.reject_ai_session_card("3", None).is_err());
+
+        let cards = col.load_ai_session(Default::default()).unwrap().cards;
+        assert_eq!(cards[0].status(), CardStatus::Approved);
+        assert_eq!(cards[0].reject_reason, None);
+        assert_eq!(cards[1].status(), CardStatus::Rejected);
+        assert_eq!(cards[1].reject_reason.as_deref(), Some("too vague"));
+
+        // the reason is kept when the UI saves the cards again
+        col.save_ai_session(SaveSessionRequest {
+            cards: cards.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        let reloaded = col.load_ai_session(Default::default()).unwrap().cards;
+        assert_eq!(reloaded[1].reject_reason.as_deref(), Some("too vague"));
+    }
+
+    #[test]
+    fn rejections_are_summarized_by_reason() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        assert!(col.session_rejection_stats().unwrap().is_empty());
+
+        let cards = (1..=5)
+            .map(|id| approved_card(&id.to_string(), CardType::Basic, "Q", "A"))
+            .collect();
+        col.save_ai_session(SaveSessionRequest {
+            cards,
+            ..Default::default()
+        })
+        .unwrap();
+        col.reject_ai_session_card("1", Some("too vague".to_string()))
+            .unwrap();
+        col.reject_ai_session_card("2", Some("too vague ".to_string()))
+            .unwrap();
+        col.reject_ai_session_card("3", Some("duplicate".to_string()))
+            .unwrap();
+        col.reject_ai_session_card("4", None).unwrap();
+
+        let stats = col.session_rejection_stats().unwrap();
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats["too vague"], 2);
+        assert_eq!(stats["duplicate"], 1);
+        assert_eq!(stats[""], 1);
+    }
+
+    #[test]
+    fn generated_card_ids_are_unique() {
+        let ids: HashSet<String> = (0..10_000).map(|_| new_session_card_id()).collect();
+        assert_eq!(ids.len(), 10_000);
+        assert!(ids.iter().all(|id| id.len() == 36));
+    }
+
+    #[test]
+    fn appended_cards_are_given_ids() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+            ..Default::default()
+        })
+        .unwrap();
+        let cards = parse_openai_response(
+            r#"{"cards": [{"type": "basic", "front": "Q2", "back": "A2"},
+                          {"type": "basic", "front": "Q3", "back": "A3"}]}"#,
+        )
+        .unwrap();
+        let ids = col.append_ai_session_cards(cards).unwrap();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+
+        let cards = col.load_ai_session(Default::default()).unwrap().cards;
+        let stored: Vec<_> = cards.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(stored, ["1", ids[0].as_str(), ids[1].as_str()]);
+    }
+
+    #[test]
+    fn sessions_can_be_diffed() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let save = |cards| {
+            col.save_ai_session(SaveSessionRequest {
+                cards,
+                ..Default::default()
+            })
+            .unwrap();
+            col.ai_session_snapshot("").unwrap().unwrap()
+        };
+        let old = save(vec![
+            approved_card("1", CardType::Basic, "Q1", "A1"),
+            approved_card("2", CardType::Basic, "Q2", "A2"),
+            approved_card("3", CardType::Basic, "Q3", "A3"),
+            approved_card("4", CardType::Basic, "Q4", "A4"),
+        ]);
+        let new = save(vec![
+            approved_card("1", CardType::Basic, "Q1", "A1"),
+            approved_card("3", CardType::Basic, "Q3", "edited"),
+            // regenerated with a new id, but otherwise unchanged
+            approved_card("40", CardType::Basic, "Q4", "A4"),
+            approved_card("5", CardType::Basic, "Q5", "A5"),
+        ]);
+        assert_eq!(
+            diff_sessions(&old, &new),
+            SessionDiff {
+                added: vec!["5".to_string()],
+                removed: vec!["2".to_string()],
+                modified: vec!["3".to_string()],
+            }
+        );
+        assert_eq!(diff_sessions(&new, &new), SessionDiff::default());
+    }
+
+    #[test]
+    fn validation_results_survive_save_and_load() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![
+                approved_card("1", CardType::Basic, "Q", "A"),
+                approved_card("2", CardType::Cloze, "{{c1::a {{c2::b}} c}}", ""),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let cards = col.load_ai_session(Default::default()).unwrap().cards;
+        let validation = cards[0].validation.as_ref().unwrap();
+        assert!(validation.valid);
+        assert!(validation.issues.is_empty());
+        let validation = cards[1].validation.as_ref().unwrap();
+        assert!(validation.valid);
+        assert_eq!(validation.issues.len(), 1);
+        assert!(validation.issues[0].contains("nested"));
+
+        // older sessions have no stored results
+        let json = fs::read_to_string(col.ai_session_path().unwrap())
+            .unwrap()
+            .replace("\"validation\"", "\"ignored\"");
+        fs::write(col.ai_session_path().unwrap(), json).unwrap();
+        let cards = col.load_ai_session(Default::default()).unwrap().cards;
+        assert_eq!(cards[1].validation, None);
+    }
+
+    #[test]
+    fn session_requires_a_collection_folder() {
+        // an in-memory collection has a bare file name as its path
+        let col = Collection::new();
+        let err = col
+            .save_ai_session(SaveSessionRequest {
+                cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+                ..Default::default()
+            })
+            .unwrap_err();
+        assert_eq!(
+            err,
+            AnkiError::AiFlashcardsError {
+                source: AiFlashcardsError::SessionUnavailable
+            }
+        );
+        assert!(col.load_ai_session(Default::default()).is_err());
+
+        // nor is it stored in the root folder
+        let mut col = Collection::new();
+        col.col_path = PathBuf::from("/collection.anki2");
+        assert_eq!(
+            col.ai_session_path().unwrap_err(),
+            AnkiError::AiFlashcardsError {
+                source: AiFlashcardsError::SessionUnavailable
+            }
+        );
+    }
+
+    #[test]
+    fn tags_can_be_changed_across_session() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let mut tagged = approved_card("2", CardType::Basic, "Q2", "A2");
+        tagged.suggested_tags = vec!["Cell_Biology".to_string()];
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "A"), tagged],
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(col.add_tag_to_session("cell biology").unwrap(), 1);
+        let tags = |col: &Collection| -> Vec<Vec<String>> {
+            col.load_ai_session(Default::default())
+                .unwrap()
+                .cards
+                .into_iter()
+                .map(|card| card.suggested_tags)
+                .collect()
+        };
+        assert_eq!(tags(&col), [vec!["cell_biology"], vec!["Cell_Biology"]]);
+        assert!(col.add_tag_to_session("!!").is_err());
+
+        assert_eq!(col.remove_tag_from_session("Cell Biology").unwrap(), 2);
+        assert_eq!(tags(&col), [Vec::<String>::new(), vec![]]);
+    }
+
+    #[test]
+    fn session_can_be_revalidated_with_new_rules() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![approved_card(
+                "1",
+                CardType::Cloze,
+                "{{c1::mitochondria}}<script>x()</script> make ATP",
+                "",
+            )],
+            ..Default::default()
+        })
+        .unwrap();
+        let validation = |col: &Collection| {
+            col.load_ai_session(Default::default()).unwrap().cards[0]
+                .validation
+                .clone()
+                .unwrap()
+        };
+        assert!(validation(&col).issues.is_empty());
+
+        let stricter = ValidationConfig {
+            max_cloze_answer_chars: 5,
+            ..Default::default()
+        };
+        assert_eq!(col.revalidate_session(&stricter).unwrap(), 0);
+        assert_eq!(validation(&col).issues.len(), 1);
+        let cards = col.load_ai_session(Default::default()).unwrap().cards;
+        assert_eq!(cards[0].front, "{{c1::mitochondria}} make ATP");
+    }
+
+    #[test]
+    fn session_errors_have_specific_causes() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let cause = |err| match err {
+            AnkiError::AiFlashcardsError { source } => source,
+            other => panic!("unexpected error: {other:?}"),
+        };
+        let err = col.add_tag_to_session("bio").unwrap_err();
+        assert_eq!(cause(err), AiFlashcardsError::SessionMissing);
+
+        col.save_ai_session(SaveSessionRequest {
+            passphrase: "secret".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let err = col.add_tag_to_session("bio").unwrap_err();
+        assert_eq!(cause(err), AiFlashcardsError::SessionLocked);
+
+        fs::write(col.ai_session_path().unwrap(), "{not json").unwrap();
+        let err = col.load_ai_session(Default::default()).unwrap_err();
+        assert!(matches!(
+            cause(err),
+            AiFlashcardsError::SessionCorrupt { .. }
+        ));
+    }
+
+    #[test]
+    fn concurrent_changes_are_not_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SESSION_FILENAME);
+        let session = SessionFile {
+            version: SESSION_VERSION,
+            id: "session".to_string(),
+            created_timestamp: TimestampSecs::now().0,
+            source_name: String::new(),
+            source_text: String::new(),
+            cards: vec![],
+            finalized: false,
+            validation_config: None,
+        };
+        write_session_file_at(&path, &session, "").unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|thread| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for n in 0..10 {
+                        with_session_lock(&path, || {
+                            let StoredSession::Loaded(mut session) =
+                                read_session_file_at(&path, "")?
+                            else {
+                                panic!("session should be readable");
+                            };
+                            let card =
+                                approved_card(&format!("{thread}-{n}"), CardType::Basic, "Q", "A");
+                            session
+                                .cards
+                                .push(SessionCard::new(&card, None, &Default::default()));
+                            write_session_file_at(&path, &session, "")
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let StoredSession::Loaded(session) = read_session_file_at(&path, "").unwrap() else {
+            panic!("session should be readable");
+        };
+        assert_eq!(session.cards.len(), 40);
+        // only the session file remains
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn session_version_is_read_without_the_cards() {
+        let (col, dir) = open_fs_test_collection("ai_session");
+        let path = col.ai_session_path().unwrap();
+        assert_eq!(session_version(&path), None);
+
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(session_version(&path), Some(SESSION_VERSION));
+
+        // older files are still read, even if their cards are incompatible
+        let older = dir.path().join("older_session.json");
+        fs::write(&older, r#"{"version": 0, "cards": [{"unknown": true}]}"#).unwrap();
+        assert_eq!(session_version(&older), Some(0));
+
+        fs::write(&older, "not json").unwrap();
+        assert_eq!(session_version(&older), None);
+    }
+
+    #[test]
+    fn finalized_flag_survives_save_and_load() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let save = |finalized| {
+            col.save_ai_session(SaveSessionRequest {
+                cards: vec![approved_card("1", CardType::Basic, "Q", "A")],
+                session_id: "session".to_string(),
+                finalized,
+                ..Default::default()
+            })
+            .unwrap();
+            col.load_ai_session(Default::default()).unwrap().finalized
+        };
+        assert!(!save(false));
+        assert!(save(true));
+        assert!(col.ai_session_finalized("session").unwrap());
+        assert!(!col.ai_session_finalized("other").unwrap());
+    }
+
+    #[test]
+    fn suppressed_warnings_survive_save_and_revalidation() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        let save = |cards| {
+            col.save_ai_session(SaveSessionRequest {
+                cards,
+                ..Default::default()
+            })
+            .unwrap();
+        };
+        let load = || col.load_ai_session(Default::default()).unwrap().cards;
+        let stricter = ValidationConfig {
+            max_cloze_answer_chars: 5,
+            ..Default::default()
+        };
+        save(vec![approved_card(
+            "1",
+            CardType::Cloze,
+            "{{c1::mitochondria}} make ATP",
+            "",
+        )]);
+        col.revalidate_session(&stricter).unwrap();
+        let mut cards = load();
+        let warning = cards[0].validation.clone().unwrap().issues[0].clone();
+
+        cards[0].suppressed_warnings = vec![warning];
+        save(cards.clone());
+        assert_eq!(load()[0].suppressed_warnings, cards[0].suppressed_warnings);
+        col.revalidate_session(&stricter).unwrap();
+        let cards = load();
+        assert!(cards[0].validation.clone().unwrap().issues.is_empty());
+        assert_eq!(cards[0].suppressed_warnings.len(), 1);
+    }
+
+    #[test]
+    fn saving_keeps_the_last_validation_config() {
+        let (col, _dir) = open_fs_test_collection("ai_session");
+        // like the frontend, sessions are saved without an id
+        let save = |cards| {
+            col.save_ai_session(SaveSessionRequest {
+                cards,
+                ..Default::default()
+            })
+            .unwrap();
+        };
+        let load = || col.load_ai_session(Default::default()).unwrap().cards;
+        let issues = |card: &GeneratedCard| card.validation.clone().unwrap().issues.len();
+        save(vec![
+            approved_card("1", CardType::Cloze, "{{c1::mitochondria}} make ATP", ""),
+            approved_card("2", CardType::Cloze, "{{c1::ATP}} stores energy", ""),
+        ]);
+        assert_eq!(load().iter().map(issues).collect::<Vec<_>>(), [0, 0]);
+
+        col.revalidate_session(&ValidationConfig {
+            max_cloze_answer_chars: 5,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut cards = load();
+        assert_eq!(cards.iter().map(issues).collect::<Vec<_>>(), [1, 0]);
+
+        // unchanged cards keep their result, and edited ones are checked with
+        // the same rules
+        cards[1].front = "{{c1::adenosine triphosphate}} stores energy".to_string();
+        save(cards);
+        assert_eq!(load().iter().map(issues).collect::<Vec<_>>(), [1, 1]);
+    }
+}
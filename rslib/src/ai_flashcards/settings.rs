@@ -0,0 +1,72 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Defaults for AI flashcard imports, persisted in the collection config so
+//! they don't have to be provided with every call.
+
+use std::collections::HashMap;
+
+use anki_proto::ai_flashcards::CardType;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::card_type_name;
+use crate::prelude::*;
+
+/// Config key holding the persisted [AiSettings].
+const SETTINGS_KEY: &str = "aiFlashcardsSettings";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AiSettings {
+    /// The deck cards are imported into when no target deck is provided.
+    pub default_deck: Option<DeckId>,
+    /// A tag added to every imported note.
+    pub base_tag: Option<String>,
+    /// The notetype to import each card type into, instead of the stock one,
+    /// keyed by the type's name in the model's response, eg "cloze".
+    /// Cards that name their own notetype are unaffected.
+    pub notetypes: HashMap<String, String>,
+}
+
+impl AiSettings {
+    /// The configured notetype for cards of this type, if any.
+    pub fn notetype_for(&self, card_type: CardType) -> Option<&str> {
+        self.notetypes
+            .get(card_type_name(card_type))
+            .map(String::as_str)
+            .filter(|name| !name.trim().is_empty())
+    }
+}
+
+impl Collection {
+    /// The persisted defaults, or empty defaults if none were saved.
+    pub fn get_ai_settings(&self) -> AiSettings {
+        self.get_config_default(SETTINGS_KEY)
+    }
+
+    pub fn set_ai_settings(&mut self, settings: &AiSettings) -> Result<OpOutput<()>> {
+        self.transact(Op::UpdateConfig, |col| {
+            col.set_config(SETTINGS_KEY, settings)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_deck_is_persisted() {
+        let mut col = Collection::new();
+        assert_eq!(col.get_ai_settings(), AiSettings::default());
+
+        let settings = AiSettings {
+            default_deck: Some(DeckId(5)),
+            ..Default::default()
+        };
+        col.set_ai_settings(&settings).unwrap();
+        assert_eq!(col.get_ai_settings().default_deck, Some(DeckId(5)));
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Export of reviewed cards as tab-separated values, for use in spreadsheets,
+//! and import of such files.
+
+use std::fs;
+use std::path::Path;
+
+use anki_proto::ai_flashcards::CardStatus;
+use anki_proto::ai_flashcards::GeneratedCard;
+
+use super::card_type_from_name;
+use super::card_type_name;
+use super::new_session_card_id;
+use super::AIGeneratedCard;
+use super::AiFlashcardsError;
+use crate::error;
+use crate::prelude::*;
+
+/// The first line of an exported file.
+const TSV_HEADER: &str = "type\tfront\tback\ttags\tstatus";
+
+fn card_status_name(status: CardStatus) -> &'static str {
+    match status {
+        CardStatus::Pending => "pending",
+        CardStatus::Approved => "approved",
+        CardStatus::Rejected => "rejected",
+    }
+}
+
+/// Escape a value so it fits in a single cell. Backslashes, tabs and line
+/// breaks are written as `\\`, `\t`, `\n` and `\r`.
+fn escape_tsv_field(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reverse [escape_tsv_field]. Unknown escapes are kept as they are.
+fn unescape_tsv_field(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn card_status_from_name(name: &str) -> Option<CardStatus> {
+    match name {
+        "pending" => Some(CardStatus::Pending),
+        "approved" => Some(CardStatus::Approved),
+        "rejected" => Some(CardStatus::Rejected),
+        _ => None,
+    }
+}
+
+/// Format the approved cards as TSV, with a header line. Tags are separated
+/// by spaces, as in Anki.
+pub fn cards_to_tsv(cards: &[AIGeneratedCard]) -> String {
+    let mut out = format!("{TSV_HEADER}\n");
+    for card in cards
+        .iter()
+        .filter(|card| card.status == CardStatus::Approved)
+    {
+        let row = [
+            card_type_name(card.card_type).to_string(),
+            escape_tsv_field(&card.front),
+            escape_tsv_field(&card.back),
+            escape_tsv_field(&card.suggested_tags.join(" ")),
+            card_status_name(card.status).to_string(),
+        ];
+        out.push_str(&row.join("\t"));
+        out.push('\n');
+    }
+    out
+}
+
+/// Parse TSV in the format written by [cards_to_tsv]. The header line is
+/// optional, and blank lines are ignored. Each card is given a new id.
+pub fn cards_from_tsv(tsv: &str) -> Result<Vec<AIGeneratedCard>> {
+    let mut cards = vec![];
+    for (idx, line) in tsv.lines().enumerate() {
+        if line.trim().is_empty() || (idx == 0 && line == TSV_HEADER) {
+            continue;
+        }
+        let line_number = idx + 1;
+        let cells: Vec<&str> = line.split('\t').collect();
+        let [card_type, front, back, tags, status] = cells[..] else {
+            invalid_input!("line {line_number} has {} columns, expected 5", cells.len());
+        };
+        cards.push(AIGeneratedCard {
+            id: new_session_card_id(),
+            card_type: card_type_from_name(card_type).or_invalid(format!(
+                "line {line_number} has unknown card type '{card_type}'"
+            ))?,
+            front: unescape_tsv_field(front),
+            back: unescape_tsv_field(back),
+            suggested_tags: unescape_tsv_field(tags)
+                .split_whitespace()
+                .map(ToString::to_string)
+                .collect(),
+            status: card_status_from_name(status)
+                .or_invalid(format!("line {line_number} has unknown status '{status}'"))?,
+            ..Default::default()
+        });
+    }
+    Ok(cards)
+}
+
+/// Read cards from a TSV file written by [Collection::export_session_tsv].
+pub fn import_session_tsv(path: impl AsRef<Path>) -> Result<Vec<AIGeneratedCard>> {
+    cards_from_tsv(&fs::read_to_string(path)?)
+}
+
+impl Collection {
+    /// Write the approved cards of the stored (unencrypted) session to a TSV
+    /// file.
+    pub fn export_session_tsv(&self, path: impl AsRef<Path>) -> error::Result<()> {
+        let session = self
+            .ai_session_snapshot("")?
+            .ok_or(AiFlashcardsError::SessionMissing)?;
+        let cards: Vec<AIGeneratedCard> = session
+            .into_cards()
+            .into_iter()
+            .map(|card| AIGeneratedCard::from(GeneratedCard::from(card)))
+            .collect();
+        fs::write(path, cards_to_tsv(&cards))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anki_proto::ai_flashcards::CardType;
+    use anki_proto::ai_flashcards::SaveSessionRequest;
+
+    use super::*;
+    use crate::tests::open_fs_test_collection;
+
+    fn generated_card(front: &str, back: &str, status: CardStatus) -> GeneratedCard {
+        GeneratedCard {
+            id: front.to_string(),
+            card_type: CardType::Basic.into(),
+            front: front.to_string(),
+            back: back.to_string(),
+            suggested_tags: vec!["bio".to_string(), "cells".to_string()],
+            status: status.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn approved_cards_are_exported() {
+        let (col, dir) = open_fs_test_collection("ai_session");
+        col.save_ai_session(SaveSessionRequest {
+            cards: vec![
+                generated_card("Q", "line one\nline\ttwo \\ three", CardStatus::Approved),
+                generated_card("Rejected", "A", CardStatus::Rejected),
+            ],
+            source_name: "notes.pdf".to_string(),
+            source_text: "text".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let path = dir.path().join("cards.tsv");
+        col.export_session_tsv(&path).unwrap();
+        let tsv = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = tsv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], TSV_HEADER);
+        let cells: Vec<&str> = lines[1].split('\t').collect();
+        assert_eq!(
+            cells,
+            [
+                "basic",
+                "Q",
+                "line one\\nline\\ttwo \\\\ three",
+                "bio cells",
+                "approved"
+            ]
+        );
+    }
+
+    #[test]
+    fn cards_round_trip_through_tsv() {
+        let cards = vec![
+            AIGeneratedCard::from(generated_card(
+                "Q\twith tab",
+                "two\r\nlines \\n",
+                CardStatus::Approved,
+            )),
+            AIGeneratedCard {
+                card_type: CardType::Cloze,
+                front: "{{c1::Paris}}".to_string(),
+                back: String::new(),
+                suggested_tags: vec![],
+                ..AIGeneratedCard::from(generated_card("", "", CardStatus::Approved))
+            },
+        ];
+        let imported = cards_from_tsv(&cards_to_tsv(&cards)).unwrap();
+        assert_eq!(imported.len(), 2);
+        for (imported, original) in imported.iter().zip(&cards) {
+            assert_eq!(
+                AIGeneratedCard {
+                    id: original.id.clone(),
+                    ..imported.clone()
+                },
+                *original
+            );
+        }
+
+        // rows with the wrong number of columns are rejected
+        let err = cards_from_tsv("basic\tQ\tA\n").unwrap_err();
+        assert!(matches!(err, AnkiError::InvalidInput { .. }));
+        // the header is optional
+        assert_eq!(
+            cards_from_tsv("cloze\t{{c1::a}}\t\t\tpending")
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,1398 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Validation of AI-generated cards before they are shown for review or
+//! imported into the collection.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+use anki_proto::ai_flashcards::CardType;
+use regex::Regex;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+use super::convert::content_guid;
+use super::parse::parse_raw_response;
+use super::sanitize::plain_text;
+use super::sanitize::sanitize_card_html;
+use super::sanitize::sanitize_card_html_fast;
+use super::sanitize::sanitize_card_markdown;
+use super::sanitize::sanitize_tag;
+use super::sanitize::strip_empty_blocks;
+use super::sanitize::TagSanitizeConfig;
+use super::AIGeneratedCard;
+use crate::import_export::ImportProgress;
+use crate::prelude::*;
+use crate::progress::ThrottlingProgressHandler;
+use crate::text::extract_media_refs;
+use crate::text::strip_html;
+
+/// Matches a cloze deletion, capturing its index, answer and optional hint.
+/// The hint separator is the last `::` of a run of colons, so the answer of
+/// `{{c1::a:::b}}` is `a:`, and its hint `b`.
+pub static CLOZE_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)\{\{c(\d+)::(.*?)(?:::((?:[^:].*?)?))?\}\}").unwrap());
+
+static SPACE_RUN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[ \t]{2,}").unwrap());
+
+/// Matches an opening or closing HTML tag, capturing the slash and name.
+static HTML_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<(/?)([a-zA-Z][a-zA-Z0-9]*)").unwrap());
+
+/// Matches an image's alt text, capturing it in one of three groups depending
+/// on how it is quoted.
+static IMG_ALT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)<img\b[^>]*?\balt\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#).unwrap()
+});
+
+/// The distinct cloze numbers used in the text, in ascending order.
+pub fn cloze_indices(text: &str) -> Vec<u32> {
+    cloze_indices_matching(&CLOZE_PATTERN, text)
+}
+
+fn cloze_indices_matching(pattern: &Regex, text: &str) -> Vec<u32> {
+    let mut indices: Vec<u32> = pattern
+        .captures_iter(text)
+        .filter_map(|caps| caps[1].parse().ok())
+        .collect();
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+}
+
+/// Ordered from least to most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum IssueSeverity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    pub severity: IssueSeverity,
+    /// The field the issue relates to, eg "front" or "card_type".
+    pub field: String,
+    pub message: String,
+}
+
+impl ValidationIssue {
+    fn new(severity: IssueSeverity, field: &str, message: impl Into<String>) -> Self {
+        ValidationIssue {
+            severity,
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationResult {
+    /// False if any issue has [IssueSeverity::Error].
+    pub valid: bool,
+    /// The card after any automatic corrections.
+    pub card: AIGeneratedCard,
+    pub issues: Vec<ValidationIssue>,
+    /// Suggested tags that were altered or dropped by sanitization.
+    pub tag_changes: Vec<TagChange>,
+    /// The fields ("front" or "back") that only contained unsafe content, and
+    /// were left empty by sanitization.
+    pub emptied_fields: Vec<&'static str>,
+}
+
+/// A suggested tag that sanitization changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagChange {
+    pub original: String,
+    /// The tag that was kept, or `None` if it was dropped because nothing
+    /// usable remained, or it duplicated an earlier tag.
+    pub sanitized: Option<String>,
+}
+
+impl ValidationResult {
+    /// The severity of the most serious issue, if there are any issues.
+    pub fn max_severity(&self) -> Option<IssueSeverity> {
+        self.issues.iter().map(|issue| issue.severity).max()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.has_severity(IssueSeverity::Error)
+    }
+
+    pub fn has_warnings(&self) -> bool {
+        self.has_severity(IssueSeverity::Warning)
+    }
+
+    fn has_severity(&self, severity: IssueSeverity) -> bool {
+        self.issues.iter().any(|issue| issue.severity == severity)
+    }
+}
+
+/// A custom pattern for recognizing cloze deletions, for notetypes that use
+/// other delimiters.
+#[derive(Debug, Clone)]
+pub struct ClozePattern(Regex);
+
+impl ClozePattern {
+    /// The pattern must capture the cloze number in its first group and the
+    /// answer in its second, like [CLOZE_PATTERN].
+    pub fn new(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern).or_invalid("invalid cloze pattern")?;
+        require!(
+            regex.captures_len() >= 3,
+            "cloze pattern must capture the number and the answer"
+        );
+        Ok(ClozePattern(regex))
+    }
+}
+
+impl Serialize for ClozePattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ClozePattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        ClozePattern::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    /// Correct the card type when the content clearly belongs to another
+    /// type, instead of only reporting the mismatch.
+    pub auto_fix_type: bool,
+    /// Cloze deletions whose answer is longer than this many characters
+    /// (excluding HTML) produce a warning.
+    pub max_cloze_answer_chars: usize,
+    pub tags: TagSanitizeConfig,
+    /// Severity of the issue raised when a non-cloze card's front and back
+    /// are the same.
+    pub identical_sides_severity: IssueSeverity,
+    /// Phrases that indicate the model leaked commentary into a card, such as
+    /// "As an AI language model". Matched case-insensitively.
+    pub boilerplate_phrases: Vec<String>,
+    /// Remove boilerplate phrases from fields instead of only reporting them.
+    pub auto_strip_boilerplate: bool,
+    /// Recognizes cloze deletions; [CLOZE_PATTERN] if not set.
+    pub cloze_pattern: Option<ClozePattern>,
+    /// Fields are Markdown to be stored as-is, for rendering by an add-on.
+    /// Instead of sanitizing them as HTML, anything that could start an HTML
+    /// tag is escaped.
+    pub treat_as_markdown: bool,
+    /// Fields longer than this many bytes are cleaned with
+    /// [sanitize_card_html_fast] instead of being fully sanitized, to speed up
+    /// bulk imports. See that function for its weaker guarantees.
+    pub fast_sanitize_above: Option<usize>,
+    /// Non-cloze card types that may have an empty back, for notetypes such
+    /// as image-only prompts. Cloze cards may always have an empty back.
+    pub allow_empty_back: Vec<CardType>,
+}
+
+impl ValidationConfig {
+    fn cloze_regex(&self) -> &Regex {
+        match &self.cloze_pattern {
+            Some(pattern) => &pattern.0,
+            None => &CLOZE_PATTERN,
+        }
+    }
+}
+
+/// Phrases that models commonly add around their output.
+pub const DEFAULT_BOILERPLATE_PHRASES: [&str; 6] = [
+    "as an ai language model",
+    "as a large language model",
+    "here are your flashcards",
+    "here are the flashcards",
+    "here is the answer:",
+    "i hope this helps",
+];
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            auto_fix_type: false,
+            max_cloze_answer_chars: 150,
+            tags: TagSanitizeConfig::default(),
+            identical_sides_severity: IssueSeverity::Error,
+            boilerplate_phrases: DEFAULT_BOILERPLATE_PHRASES
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            auto_strip_boilerplate: false,
+            cloze_pattern: None,
+            treat_as_markdown: false,
+            fast_sanitize_above: None,
+            allow_empty_back: vec![],
+        }
+    }
+}
+
+/// Reduce text to its visible content for comparison, ignoring HTML, case and
+/// whitespace differences.
+pub fn normalize_for_compare(text: &str) -> String {
+    plain_text(text).to_lowercase()
+}
+
+/// Criteria a card must meet to be imported when a quality gate is used.
+#[derive(Debug, Clone, Default)]
+pub struct QualityGate {
+    /// Cards with a lower confidence are rejected. Cards without a
+    /// confidence score only pass if this is 0.
+    pub min_confidence: f32,
+    pub validation: ValidationConfig,
+}
+
+impl QualityGate {
+    /// True if the card is valid, has no warnings, and is confident enough.
+    pub fn passes(&self, card: &AIGeneratedCard) -> bool {
+        let result = validate_card(card, &self.validation);
+        result.valid
+            && result
+                .issues
+                .iter()
+                .all(|issue| issue.severity == IssueSeverity::Info)
+            && card.confidence.unwrap_or(0.0) >= self.min_confidence
+    }
+}
+
+/// Check a single card, returning the (possibly corrected) card along with
+/// any issues found.
+pub fn validate_card(card: &AIGeneratedCard, config: &ValidationConfig) -> ValidationResult {
+    let mut card = card.clone();
+    let mut issues = vec![];
+
+    let front_emptied = sanitize_field(&mut card.front, "front", config, &mut issues);
+    let back_emptied = sanitize_field(&mut card.back, "back", config, &mut issues);
+    if card.card_type == CardType::Cloze && !config.treat_as_markdown {
+        if let Cow::Owned(stripped) = strip_empty_blocks(&card.front) {
+            card.front = stripped;
+        }
+    }
+    if config.auto_strip_boilerplate {
+        strip_boilerplate(&mut card.front, "front", config, &mut issues);
+        strip_boilerplate(&mut card.back, "back", config, &mut issues);
+    }
+    check_card_type(&mut card, config, &mut issues);
+    if card.card_type == CardType::Cloze {
+        check_cloze_lengths(&card, config, &mut issues);
+        check_nested_clozes(&card, config, &mut issues);
+    }
+    let tag_changes = sanitize_card_tags(&mut card, config, &mut issues);
+    check_media_refs(&card.front, "front", &mut issues);
+    check_media_refs(&card.back, "back", &mut issues);
+    check_alt_text_leak(&card, &mut issues);
+    check_boilerplate(&card.front, "front", config, &mut issues);
+    check_boilerplate(&card.back, "back", config, &mut issues);
+
+    if card.front.trim().is_empty() && !front_emptied {
+        issues.push(ValidationIssue::new(
+            IssueSeverity::Error,
+            "front",
+            "Front field is empty",
+        ));
+    }
+    if card.card_type != CardType::Cloze
+        && !config.allow_empty_back.contains(&card.card_type)
+        && card.back.trim().is_empty()
+        && !back_emptied
+    {
+        issues.push(ValidationIssue::new(
+            IssueSeverity::Error,
+            "back",
+            "Back field is empty for non-cloze card",
+        ));
+    }
+
+    if card.card_type != CardType::Cloze
+        && !card.back.trim().is_empty()
+        && normalize_for_compare(&card.front) == normalize_for_compare(&card.back)
+    {
+        issues.push(ValidationIssue::new(
+            config.identical_sides_severity,
+            "back",
+            "Front and back are identical",
+        ));
+    }
+
+    ValidationResult {
+        valid: !issues.iter().any(|i| i.severity == IssueSeverity::Error),
+        card,
+        issues,
+        tag_changes,
+        emptied_fields: [("front", front_emptied), ("back", back_emptied)]
+            .into_iter()
+            .filter_map(|(name, emptied)| emptied.then_some(name))
+            .collect(),
+    }
+}
+
+/// Score from 0 to 100 of how ready a card is to import, for sorting cards
+/// worst-first. Starting from 100, the card loses:
+/// - 50 for each validation error
+/// - 15 for each warning
+/// - 5 for each info note
+/// - 40 if its [content_guid] is in `existing_fingerprints`, as it is then
+///   likely a duplicate
+///
+/// The score doesn't go below 0.
+pub fn import_readiness(card: &AIGeneratedCard, existing_fingerprints: &HashSet<String>) -> u8 {
+    let result = validate_card(card, &ValidationConfig::default());
+    let mut penalty: u32 = result
+        .issues
+        .iter()
+        .map(|issue| match issue.severity {
+            IssueSeverity::Error => 50,
+            IssueSeverity::Warning => 15,
+            IssueSeverity::Info => 5,
+        })
+        .sum();
+    if existing_fingerprints.contains(&content_guid(card)) {
+        penalty += 40;
+    }
+    100u32.saturating_sub(penalty) as u8
+}
+
+/// Validate cards in order, stopping at the first invalid one, for a quick
+/// go/no-go check on large batches. Returns the index of that card and its
+/// result, or `None` if every card is valid.
+pub fn validate_cards_until_first_error<'a>(
+    cards: impl IntoIterator<Item = &'a AIGeneratedCard>,
+    config: &ValidationConfig,
+) -> Option<(usize, ValidationResult)> {
+    cards
+        .into_iter()
+        .map(|card| validate_card(card, config))
+        .enumerate()
+        .find(|(_, result)| !result.valid)
+}
+
+/// Find pairs of cards in a batch whose words overlap heavily, such as
+/// paraphrases of the same question, so reviewers can merge them. The score is
+/// the Jaccard similarity of the words on both sides of each card, ignoring
+/// HTML and case; pairs scoring at least `threshold` (from 0 to 1) are
+/// returned as `(first index, second index, score)`, in index order.
+pub fn find_similar_pairs(cards: &[AIGeneratedCard], threshold: f32) -> Vec<(usize, usize, f32)> {
+    let words: Vec<HashSet<String>> = cards
+        .iter()
+        .map(|card| {
+            normalize_for_compare(&format!("{} {}", card.front, card.back))
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        })
+        .collect();
+    let mut pairs = vec![];
+    for (i, first) in words.iter().enumerate() {
+        for (j, second) in words.iter().enumerate().skip(i + 1) {
+            let union = first.union(second).count();
+            if union == 0 {
+                continue;
+            }
+            let score = first.intersection(second).count() as f32 / union as f32;
+            if score >= threshold {
+                pairs.push((i, j, score));
+            }
+        }
+    }
+    pairs
+}
+
+/// Tags that are suggested with different casing across a batch, such as
+/// `Biology` and `biology`, so they can be unified before import. Each entry
+/// holds the lowercased tag and its spellings, both in the order they first
+/// appear.
+pub fn tag_case_conflicts(cards: &[AIGeneratedCard]) -> Vec<(String, Vec<String>)> {
+    let mut spellings: Vec<(String, Vec<String>)> = vec![];
+    for tag in cards.iter().flat_map(|card| &card.suggested_tags) {
+        let lower = tag.to_lowercase();
+        match spellings.iter_mut().find(|(key, _)| *key == lower) {
+            Some((_, seen)) => {
+                if !seen.contains(tag) {
+                    seen.push(tag.clone());
+                }
+            }
+            None => spellings.push((lower, vec![tag.clone()])),
+        }
+    }
+    spellings.retain(|(_, seen)| seen.len() > 1);
+    spellings
+}
+
+/// Add a warning to both results of every pair found by
+/// [find_similar_pairs]. `results` must be in the same order as the cards
+/// they were validated from.
+pub fn flag_similar_cards(results: &mut [ValidationResult], threshold: f32) {
+    let cards: Vec<AIGeneratedCard> = results.iter().map(|r| r.card.clone()).collect();
+    for (first, second, score) in find_similar_pairs(&cards, threshold) {
+        let percent = (score * 100.0).round();
+        for (idx, other) in [(first, second), (second, first)] {
+            results[idx].issues.push(ValidationIssue::new(
+                IssueSeverity::Warning,
+                "front",
+                format!(
+                    "This card is {percent}% similar to card '{}'; consider merging them",
+                    cards[other].id
+                ),
+            ));
+        }
+    }
+}
+
+/// The outcome of [validate_cards_with_progress].
+#[derive(Debug, Clone)]
+pub struct BatchValidation {
+    /// Results for the cards validated, in order. If the validation was
+    /// cancelled, this only covers the cards before the cancellation.
+    pub results: Vec<ValidationResult>,
+    pub cancelled: bool,
+}
+
+/// Validate a large batch of cards, reporting the number validated so far as
+/// [ImportProgress::Notes]. If the user cancels, validation stops cleanly and
+/// the results so far are returned.
+pub fn validate_cards_with_progress(
+    cards: &[AIGeneratedCard],
+    config: &ValidationConfig,
+    progress: &mut ThrottlingProgressHandler<ImportProgress>,
+) -> Result<BatchValidation> {
+    let mut results = Vec::with_capacity(cards.len());
+    let mut outcome = Ok(());
+    {
+        let mut incrementor = progress.incrementor(ImportProgress::Notes);
+        for card in cards {
+            results.push(validate_card(card, config));
+            outcome = incrementor.increment();
+            if outcome.is_err() {
+                break;
+            }
+        }
+    }
+    // the incrementor only reports every few cards, so report the total
+    if outcome.is_ok() {
+        outcome = progress.set(ImportProgress::Notes(results.len()));
+    }
+    match outcome {
+        Ok(()) => Ok(BatchValidation {
+            results,
+            cancelled: false,
+        }),
+        Err(AnkiError::Interrupted) => Ok(BatchValidation {
+            results,
+            cancelled: true,
+        }),
+        Err(err) => Err(err),
+    }
+}
+
+/// Parse the model's response and validate each card as it is converted.
+pub fn parse_and_validate(json: &str, config: &ValidationConfig) -> Result<Vec<ValidationResult>> {
+    Ok(parse_raw_response(json)?
+        .cards
+        .into_iter()
+        .map(|raw| validate_card(&raw.into(), config))
+        .collect())
+}
+
+/// Sanitize the HTML of a field in place. Returns true if this reduced
+/// non-empty content to nothing, in which case an error has been recorded.
+fn sanitize_field(
+    field: &mut String,
+    name: &str,
+    config: &ValidationConfig,
+    issues: &mut Vec<ValidationIssue>,
+) -> bool {
+    if config.treat_as_markdown {
+        if let Cow::Owned(escaped) = sanitize_card_markdown(field) {
+            issues.push(ValidationIssue::new(
+                IssueSeverity::Info,
+                name,
+                format!("HTML in the {name} field was escaped, as it is treated as Markdown"),
+            ));
+            *field = escaped;
+        }
+        return false;
+    }
+    let fast = config
+        .fast_sanitize_above
+        .is_some_and(|limit| field.len() > limit);
+    let sanitized = if fast {
+        sanitize_card_html_fast(field)
+    } else {
+        sanitize_card_html(field)
+    };
+    let emptied = sanitized.trim().is_empty() && !field.trim().is_empty();
+    if emptied {
+        issues.push(ValidationIssue::new(
+            IssueSeverity::Error,
+            name,
+            format!("The {name} field only contained content that was removed as unsafe"),
+        ));
+    }
+    // the fast path doesn't repair HTML, so there is nothing to report
+    if !emptied && !fast && !is_subsequence(&tag_structure(&sanitized), &tag_structure(field)) {
+        issues.push(ValidationIssue::new(
+            IssueSeverity::Warning,
+            name,
+            format!("The {name} field contained malformed HTML, which was corrected"),
+        ));
+    }
+    *field = sanitized;
+    emptied
+}
+
+/// The opening and closing tags in the HTML, in order, eg `["div", "/div"]`.
+fn tag_structure(html: &str) -> Vec<String> {
+    HTML_TAG
+        .captures_iter(html)
+        .map(|caps| format!("{}{}", &caps[1], caps[2].to_ascii_lowercase()))
+        .collect()
+}
+
+/// True if all items of `needle` appear in `haystack` in the same order. If
+/// sanitizing only removed content, the sanitized tags are a subsequence of
+/// the original ones; otherwise tags were added or moved to repair the HTML.
+fn is_subsequence(needle: &[String], haystack: &[String]) -> bool {
+    let mut haystack = haystack.iter();
+    needle
+        .iter()
+        .all(|item| haystack.any(|other| other == item))
+}
+
+/// Detect cards whose content doesn't match their declared type, fixing the
+/// type if configured to do so.
+fn check_card_type(
+    card: &mut AIGeneratedCard,
+    config: &ValidationConfig,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    let has_cloze = !cloze_indices_matching(config.cloze_regex(), &card.front).is_empty();
+    match card.card_type {
+        CardType::Basic | CardType::BasicReversed if has_cloze => {
+            if config.auto_fix_type {
+                card.card_type = CardType::Cloze;
+                issues.push(ValidationIssue::new(
+                    IssueSeverity::Info,
+                    "card_type",
+                    "Front contains cloze deletions; card type changed to cloze",
+                ));
+            } else {
+                issues.push(ValidationIssue::new(
+                    IssueSeverity::Warning,
+                    "card_type",
+                    "Front contains cloze deletions; card should probably be a cloze",
+                ));
+            }
+        }
+        CardType::Cloze if !has_cloze => {
+            if config.auto_fix_type && !card.back.trim().is_empty() {
+                card.card_type = CardType::Basic;
+                issues.push(ValidationIssue::new(
+                    IssueSeverity::Info,
+                    "card_type",
+                    "Cloze card has no deletions; card type changed to basic",
+                ));
+            } else {
+                issues.push(ValidationIssue::new(
+                    IssueSeverity::Error,
+                    "front",
+                    "Cloze card has no cloze deletions",
+                ));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Warn about deletions that hide too much text to be recalled as a unit.
+fn check_cloze_lengths(
+    card: &AIGeneratedCard,
+    config: &ValidationConfig,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    for caps in config.cloze_regex().captures_iter(&card.front) {
+        let answer = strip_html(&caps[2]);
+        let len = answer.chars().count();
+        if len > config.max_cloze_answer_chars {
+            issues.push(ValidationIssue::new(
+                IssueSeverity::Warning,
+                "front",
+                format!(
+                    "Cloze deletion c{} is {len} characters long; consider splitting it (limit {})",
+                    &caps[1], config.max_cloze_answer_chars
+                ),
+            ));
+        }
+    }
+}
+
+/// Warn about deletions that contain other deletions, which usually means
+/// the model made a mistake. As [CLOZE_PATTERN] is lazy, the outer deletion
+/// stops at the first closing braces, so its answer contains the opening
+/// braces of the inner one.
+fn check_nested_clozes(
+    card: &AIGeneratedCard,
+    config: &ValidationConfig,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    for caps in config.cloze_regex().captures_iter(&card.front) {
+        if caps[2].contains("{{") {
+            issues.push(ValidationIssue::new(
+                IssueSeverity::Warning,
+                "front",
+                format!(
+                    "Cloze deletion c{} contains a nested deletion, which may not display correctly",
+                    &caps[1]
+                ),
+            ));
+        }
+    }
+}
+
+/// List the media files a field refers to, as the model usually can't supply
+/// them, and the card will be broken unless they are added separately.
+fn check_media_refs(field: &str, name: &str, issues: &mut Vec<ValidationIssue>) {
+    let mut fnames: Vec<String> = vec![];
+    for media_ref in extract_media_refs(field) {
+        let fname = media_ref.fname_decoded.into_owned();
+        if !fnames.contains(&fname) {
+            fnames.push(fname);
+        }
+    }
+    if !fnames.is_empty() {
+        issues.push(ValidationIssue::new(
+            IssueSeverity::Warning,
+            name,
+            format!(
+                "The {name} field refers to media that must be present in the collection: {}",
+                fnames.join(", ")
+            ),
+        ));
+    }
+}
+
+/// Answers shorter than this are too likely to appear in unrelated alt text,
+/// such as "a" or "1", to be checked.
+const MIN_LEAKED_ANSWER_CHARS: usize = 3;
+
+/// Warn when an image on the front has alt text containing the answer as
+/// whole words, as it can be read without studying the image.
+fn check_alt_text_leak(card: &AIGeneratedCard, issues: &mut Vec<ValidationIssue>) {
+    if card.card_type == CardType::Cloze {
+        return;
+    }
+    let answer = normalize_for_compare(&card.back);
+    let answer = answer.trim();
+    if answer.chars().count() < MIN_LEAKED_ANSWER_CHARS {
+        return;
+    }
+    let leaks = IMG_ALT.captures_iter(&card.front).any(|caps| {
+        let alt = caps
+            .iter()
+            .skip(1)
+            .flatten()
+            .next()
+            .map_or("", |m| m.as_str());
+        contains_words(&normalize_for_compare(alt), answer)
+    });
+    if leaks {
+        issues.push(ValidationIssue::new(
+            IssueSeverity::Warning,
+            "front",
+            "An image on the front has alt text that reveals the answer",
+        ));
+    }
+}
+
+/// True if `needle` occurs in `haystack` as whole words, and not only as part
+/// of longer words.
+fn contains_words(haystack: &str, needle: &str) -> bool {
+    haystack.match_indices(needle).any(|(idx, _)| {
+        let before = haystack[..idx].chars().next_back();
+        let after = haystack[idx + needle.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Warn about text the model appears to have written to the user rather than
+/// as part of the card.
+fn check_boilerplate(
+    field: &str,
+    name: &str,
+    config: &ValidationConfig,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    let text = normalize_for_compare(field);
+    for phrase in &config.boilerplate_phrases {
+        if !phrase.trim().is_empty() && text.contains(&phrase.trim().to_lowercase()) {
+            issues.push(ValidationIssue::new(
+                IssueSeverity::Warning,
+                name,
+                format!("The {name} field contains boilerplate text: \"{phrase}\""),
+            ));
+        }
+    }
+}
+
+/// Remove boilerplate phrases from a field, collapsing the whitespace left
+/// behind.
+fn strip_boilerplate(
+    field: &mut String,
+    name: &str,
+    config: &ValidationConfig,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    for phrase in &config.boilerplate_phrases {
+        let phrase = phrase.trim();
+        if phrase.is_empty() {
+            continue;
+        }
+        let Ok(re) = Regex::new(&format!("(?i){}", regex::escape(phrase))) else {
+            continue;
+        };
+        if re.is_match(field) {
+            let stripped = re.replace_all(field, " ");
+            *field = SPACE_RUN.replace_all(&stripped, " ").trim().to_string();
+            issues.push(ValidationIssue::new(
+                IssueSeverity::Info,
+                name,
+                format!("Removed boilerplate text \"{phrase}\" from the {name} field"),
+            ));
+        }
+    }
+}
+
+/// Replace the suggested tags with valid Anki tags, dropping any that are
+/// unusable or duplicated after cleanup. Returns the tags that changed.
+fn sanitize_card_tags(
+    card: &mut AIGeneratedCard,
+    config: &ValidationConfig,
+    issues: &mut Vec<ValidationIssue>,
+) -> Vec<TagChange> {
+    let mut tags: Vec<String> = vec![];
+    let mut changes = vec![];
+    for tag in &card.suggested_tags {
+        let sanitized = sanitize_tag(tag, &config.tags);
+        for warning in sanitized.warnings {
+            issues.push(ValidationIssue::new(
+                IssueSeverity::Warning,
+                "tags",
+                warning,
+            ));
+        }
+        let kept = sanitized.tag.filter(|sanitized| !tags.contains(sanitized));
+        if kept.as_ref() != Some(tag) {
+            changes.push(TagChange {
+                original: tag.clone(),
+                sanitized: kept.clone(),
+            });
+        }
+        tags.extend(kept);
+    }
+    card.suggested_tags = tags;
+    changes
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use anki_proto::ai_flashcards::CardStatus;
+
+    use super::*;
+    use crate::progress::Progress;
+    use crate::progress::ProgressState;
+
+    fn card(card_type: CardType, front: &str, back: &str) -> AIGeneratedCard {
+        AIGeneratedCard {
+            id: "1".to_string(),
+            card_type,
+            front: front.to_string(),
+            back: back.to_string(),
+            suggested_tags: vec![],
+            status: CardStatus::Pending,
+            ..Default::default()
+        }
+    }
+
+    fn has_issue(result: &ValidationResult, severity: IssueSeverity, field: &str) -> bool {
+        result
+            .issues
+            .iter()
+            .any(|i| i.severity == severity && i.field == field)
+    }
+
+    #[test]
+    fn valid_cards() {
+        let config = ValidationConfig::default();
+        let result = validate_card(&card(CardType::Basic, "Q", "A"), &config);
+        assert!(result.valid);
+        assert!(result.issues.is_empty());
+        let result = validate_card(&card(CardType::Cloze, "{{c1::Paris}} is nice", ""), &config);
+        assert!(result.valid);
+        assert!(result.issues.is_empty());
+    }
+
+    #[test]
+    fn basic_with_cloze_syntax() {
+        let basic = card(CardType::Basic, "{{c1::Paris}} is in France", "Paris");
+        let result = validate_card(&basic, &ValidationConfig::default());
+        assert!(result.valid);
+        assert!(has_issue(&result, IssueSeverity::Warning, "card_type"));
+        assert_eq!(result.card.card_type, CardType::Basic);
+
+        let reversed = card(CardType::BasicReversed, "{{c1::Paris}}", "Paris");
+        let result = validate_card(&reversed, &ValidationConfig::default());
+        assert!(has_issue(&result, IssueSeverity::Warning, "card_type"));
+
+        let config = ValidationConfig {
+            auto_fix_type: true,
+            ..Default::default()
+        };
+        let result = validate_card(&basic, &config);
+        assert!(result.valid);
+        assert!(has_issue(&result, IssueSeverity::Info, "card_type"));
+        assert_eq!(result.card.card_type, CardType::Cloze);
+    }
+
+    #[test]
+    fn cloze_without_deletions() {
+        let cloze = card(CardType::Cloze, "Paris is in France", "Capital");
+        let result = validate_card(&cloze, &ValidationConfig::default());
+        assert!(!result.valid);
+        assert!(has_issue(&result, IssueSeverity::Error, "front"));
+
+        let config = ValidationConfig {
+            auto_fix_type: true,
+            ..Default::default()
+        };
+        let result = validate_card(&cloze, &config);
+        assert!(result.valid);
+        assert_eq!(result.card.card_type, CardType::Basic);
+
+        // without a back, there's nothing sensible to fix it to
+        let cloze = card(CardType::Cloze, "Paris is in France", "");
+        let result = validate_card(&cloze, &config);
+        assert!(!result.valid);
+        assert_eq!(result.card.card_type, CardType::Cloze);
+    }
+
+    #[test]
+    fn parse_and_validate_in_one_pass() {
+        let json = r#"{"cards": [
+            {"type": "basic", "front": "Q", "back": "A"},
+            {"type": "basic", "front": "Q", "back": ""}
+        ]}"#;
+        let results = parse_and_validate(json, &ValidationConfig::default()).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].valid);
+        assert!(!results[1].valid);
+        assert!(has_issue(&results[1], IssueSeverity::Error, "back"));
+    }
+
+    #[test]
+    fn long_cloze_deletions_produce_warning() {
+        let long = "word ".repeat(40);
+        let front = format!("{{{{c1::short}}}} and {{{{c2::{long}}}}}");
+        let result = validate_card(
+            &card(CardType::Cloze, &front, ""),
+            &ValidationConfig::default(),
+        );
+        assert!(result.valid);
+        assert_eq!(result.issues.len(), 1);
+        assert_eq!(result.issues[0].severity, IssueSeverity::Warning);
+        assert!(result.issues[0].message.contains("c2"));
+
+        let config = ValidationConfig {
+            max_cloze_answer_chars: 500,
+            ..Default::default()
+        };
+        let result = validate_card(&card(CardType::Cloze, &front, ""), &config);
+        assert!(result.issues.is_empty());
+    }
+
+    #[test]
+    fn tags_are_sanitized() {
+        let mut basic = card(CardType::Basic, "Q", "A");
+        basic.suggested_tags = vec![
+            "cell biology".to_string(),
+            "cell_biology".to_string(),
+            "::bad::::tag".to_string(),
+        ];
+        let result = validate_card(&basic, &ValidationConfig::default());
+        assert!(result.valid);
+        assert_eq!(result.card.suggested_tags, ["cell_biology", "bad::tag"]);
+        assert_eq!(result.issues.len(), 1);
+        assert!(has_issue(&result, IssueSeverity::Warning, "tags"));
+    }
+
+    #[test]
+    fn fields_emptied_by_sanitization_are_errors() {
+        let basic = card(CardType::Basic, "<script>alert('hi')</script>", "A");
+        let result = validate_card(&basic, &ValidationConfig::default());
+        assert!(!result.valid);
+        assert_eq!(result.issues.len(), 1);
+        assert!(has_issue(&result, IssueSeverity::Error, "front"));
+        assert!(result.issues[0].message.contains("unsafe"));
+        assert_eq!(result.card.front, "");
+    }
+
+    #[test]
+    fn hint_separator_follows_the_answer() {
+        let parts = |text| {
+            let caps = CLOZE_PATTERN.captures(text).unwrap();
+            (
+                caps[2].to_string(),
+                caps.get(3).map(|hint| hint.as_str().to_string()),
+            )
+        };
+        assert_eq!(
+            parts("{{c1::a:::b}}"),
+            ("a:".to_string(), Some("b".to_string()))
+        );
+        assert_eq!(
+            parts("{{c1::a::b}}"),
+            ("a".to_string(), Some("b".to_string()))
+        );
+        assert_eq!(parts("{{c1::a::}}"), ("a".to_string(), Some(String::new())));
+        assert_eq!(parts("{{c1::a:b}}"), ("a:b".to_string(), None));
+    }
+
+    #[test]
+    fn cloze_indices_are_sorted_and_unique() {
+        assert_eq!(
+            cloze_indices("{{c3::a}} {{c1::b::hint}} {{c2::c}}"),
+            [1, 2, 3]
+        );
+        assert_eq!(cloze_indices("{{c2::a}} {{c1::b}} {{c2::c}}"), [1, 2]);
+        assert!(cloze_indices("no deletions {{c::x}}").is_empty());
+    }
+
+    #[test]
+    fn nested_clozes_produce_warning() {
+        let nested = card(CardType::Cloze, "{{c1::outer {{c2::inner}} text}}", "");
+        let result = validate_card(&nested, &ValidationConfig::default());
+        assert!(result.valid);
+        assert_eq!(result.issues.len(), 1);
+        assert!(has_issue(&result, IssueSeverity::Warning, "front"));
+        assert!(result.issues[0].message.contains("c1"));
+
+        let siblings = card(CardType::Cloze, "{{c1::a}} and {{c2::b}}", "");
+        let result = validate_card(&siblings, &ValidationConfig::default());
+        assert!(result.issues.is_empty());
+    }
+
+    #[test]
+    fn quality_gate_requires_clean_confident_cards() {
+        let gate = QualityGate {
+            min_confidence: 0.8,
+            ..Default::default()
+        };
+        let with_confidence =
+            |card: AIGeneratedCard, confidence| AIGeneratedCard { confidence, ..card };
+        let good = card(CardType::Basic, "Q", "A");
+        assert!(gate.passes(&with_confidence(good.clone(), Some(0.8))));
+        assert!(gate.passes(&with_confidence(good.clone(), Some(0.95))));
+        assert!(!gate.passes(&with_confidence(good.clone(), Some(0.79))));
+        assert!(!gate.passes(&with_confidence(good.clone(), None)));
+        // warnings and errors fail the gate regardless of confidence
+        let warning = card(CardType::Basic, "{{c1::Paris}}", "A");
+        assert!(!gate.passes(&with_confidence(warning, Some(1.0))));
+        let invalid = card(CardType::Basic, "Q", "");
+        assert!(!gate.passes(&with_confidence(invalid, Some(1.0))));
+
+        let lenient = QualityGate::default();
+        assert!(lenient.passes(&good));
+    }
+
+    #[test]
+    fn identical_sides_are_reported() {
+        let same = card(CardType::Basic, "<b>Paris</b>", " paris&nbsp;");
+        let result = validate_card(&same, &ValidationConfig::default());
+        assert!(!result.valid);
+        assert!(has_issue(&result, IssueSeverity::Error, "back"));
+
+        let config = ValidationConfig {
+            identical_sides_severity: IssueSeverity::Warning,
+            ..Default::default()
+        };
+        let result = validate_card(&same, &config);
+        assert!(result.valid);
+        assert!(has_issue(&result, IssueSeverity::Warning, "back"));
+
+        // cloze extra text may legitimately repeat the text
+        let cloze = card(CardType::Cloze, "{{c1::Paris}}", "{{c1::Paris}}");
+        let result = validate_card(&cloze, &ValidationConfig::default());
+        assert!(result.issues.is_empty());
+    }
+
+    #[test]
+    fn media_references_are_reported() {
+        let basic = card(
+            CardType::Basic,
+            r#"<img src="cell.png"> and <img src='nucleus.jpg'>"#,
+            "A",
+        );
+        let result = validate_card(&basic, &ValidationConfig::default());
+        assert!(result.valid);
+        assert_eq!(result.issues.len(), 1);
+        assert!(has_issue(&result, IssueSeverity::Warning, "front"));
+        assert!(result.issues[0].message.ends_with("cell.png, nucleus.jpg"));
+    }
+
+    #[test]
+    fn boilerplate_phrases_are_reported() {
+        let leaked = card(
+            CardType::Basic,
+            "What is ATP?",
+            "As an AI language model, I can say ATP stores energy",
+        );
+        let result = validate_card(&leaked, &ValidationConfig::default());
+        assert!(result.valid);
+        assert_eq!(result.issues.len(), 1);
+        assert!(has_issue(&result, IssueSeverity::Warning, "back"));
+
+        let config = ValidationConfig {
+            boilerplate_phrases: vec!["ATP stores".to_string()],
+            ..Default::default()
+        };
+        let result = validate_card(&leaked, &config);
+        assert_eq!(result.issues.len(), 1);
+        assert!(result.issues[0].message.contains("ATP stores"));
+    }
+
+    #[test]
+    fn boilerplate_can_be_stripped() {
+        let config = ValidationConfig {
+            auto_strip_boilerplate: true,
+            ..Default::default()
+        };
+        let prefixed = card(
+            CardType::Basic,
+            "What is 6 x 7?",
+            "Here is the answer:  <b>42</b>",
+        );
+        let result = validate_card(&prefixed, &config);
+        assert!(result.valid);
+        assert_eq!(result.card.back, "<b>42</b>");
+        assert_eq!(result.issues.len(), 1);
+        assert!(has_issue(&result, IssueSeverity::Info, "back"));
+    }
+
+    #[test]
+    fn severities_roll_up() {
+        assert!(IssueSeverity::Info < IssueSeverity::Warning);
+        assert!(IssueSeverity::Warning < IssueSeverity::Error);
+
+        let config = ValidationConfig {
+            auto_fix_type: true,
+            ..Default::default()
+        };
+        let result = validate_card(&card(CardType::Basic, "Q", "A"), &config);
+        assert_eq!(result.max_severity(), None);
+        assert!(!result.has_errors() && !result.has_warnings());
+
+        // info from the type fix, and a warning from the nested deletion
+        let mixed = card(CardType::Basic, "{{c1::a {{c2::b}} c}}", "A");
+        let result = validate_card(&mixed, &config);
+        assert_eq!(result.max_severity(), Some(IssueSeverity::Warning));
+        assert!(result.has_warnings());
+        assert!(!result.has_errors());
+
+        let mut invalid = mixed.clone();
+        invalid.suggested_tags = vec!["!!".to_string()];
+        invalid.front = String::new();
+        let result = validate_card(&invalid, &config);
+        assert_eq!(result.max_severity(), Some(IssueSeverity::Error));
+        assert!(result.has_errors() && result.has_warnings());
+        assert_eq!(result.valid, !result.has_errors());
+    }
+
+    #[test]
+    fn tag_changes_are_reported() {
+        let mut basic = card(CardType::Basic, "Q", "A");
+        basic.suggested_tags = ["cell biology", "genetics", "!!", "cell_biology"]
+            .map(ToString::to_string)
+            .to_vec();
+        let result = validate_card(&basic, &ValidationConfig::default());
+        let change = |original: &str, sanitized: Option<&str>| TagChange {
+            original: original.to_string(),
+            sanitized: sanitized.map(ToString::to_string),
+        };
+        assert_eq!(
+            result.tag_changes,
+            [
+                change("cell biology", Some("cell_biology")),
+                change("!!", None),
+                change("cell_biology", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn cloze_pattern_can_be_customized() {
+        let custom = card(CardType::Cloze, "[[c1::Paris]] is in [[c2::France]]", "");
+        let result = validate_card(&custom, &ValidationConfig::default());
+        assert!(!result.valid);
+
+        let config = ValidationConfig {
+            cloze_pattern: Some(ClozePattern::new(r"\[\[c(\d+)::(.*?)\]\]").unwrap()),
+            ..Default::default()
+        };
+        let result = validate_card(&custom, &config);
+        assert!(result.valid);
+        assert!(result.issues.is_empty());
+
+        assert!(ClozePattern::new(r"\[\[c(\d+").is_err());
+        // the answer must be captured
+        assert!(ClozePattern::new(r"\[\[c(\d+)::.*?\]\]").is_err());
+    }
+
+    #[test]
+    fn readiness_ranks_problem_cards_lower() {
+        let clean = card(
+            CardType::Basic,
+            "What is ATP?",
+            "The energy carrier of cells",
+        );
+        let failing = card(CardType::Basic, "What is ATP?", "");
+        let none = HashSet::new();
+        assert_eq!(import_readiness(&clean, &none), 100);
+        assert!(import_readiness(&failing, &none) < import_readiness(&clean, &none));
+
+        // likely duplicates are also ranked lower
+        let existing = HashSet::from([content_guid(&clean)]);
+        assert_eq!(import_readiness(&clean, &existing), 60);
+        assert_eq!(
+            import_readiness(&card(CardType::Basic, "", ""), &existing),
+            0
+        );
+    }
+
+    #[test]
+    fn batch_validation_stops_at_first_error() {
+        let cards = vec![
+            card(CardType::Basic, "Q1", "A1"),
+            card(CardType::Basic, "Q2", ""),
+            card(CardType::Basic, "", ""),
+            card(CardType::Basic, "Q4", "A4"),
+        ];
+        let mut checked = 0;
+        let (idx, result) = validate_cards_until_first_error(
+            cards.iter().inspect(|_| checked += 1),
+            &ValidationConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(idx, 1);
+        assert!(result.has_errors());
+        assert_eq!(checked, 2);
+
+        assert_eq!(
+            validate_cards_until_first_error(&[cards[0].clone()], &ValidationConfig::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn malformed_html_is_reported() {
+        let config = ValidationConfig::default();
+        let result = validate_card(
+            &card(CardType::Basic, "<div>What is ATP?", "Energy"),
+            &config,
+        );
+        assert!(result.valid);
+        assert!(has_issue(&result, IssueSeverity::Warning, "front"));
+        assert_eq!(result.card.front, "<div>What is ATP?</div>");
+
+        // removing unsafe content alone is not reported
+        let result = validate_card(
+            &card(
+                CardType::Basic,
+                "<div onclick=\"x()\">Q<script>x()</script></div>",
+                "A<br>",
+            ),
+            &config,
+        );
+        assert!(result.issues.is_empty());
+    }
+
+    #[test]
+    fn markdown_fields_are_kept() {
+        let config = ValidationConfig {
+            treat_as_markdown: true,
+            ..Default::default()
+        };
+        let result = validate_card(
+            &card(
+                CardType::Basic,
+                "What does **bold** do?",
+                "Use `code` <b>here</b>",
+            ),
+            &config,
+        );
+        assert!(result.valid);
+        assert_eq!(result.card.front, "What does **bold** do?");
+        assert_eq!(result.card.back, "Use `code` &lt;b>here&lt;/b>");
+        assert!(has_issue(&result, IssueSeverity::Info, "back"));
+        assert!(!has_issue(&result, IssueSeverity::Info, "front"));
+    }
+
+    #[test]
+    fn large_fields_can_be_sanitized_quickly() {
+        let config = ValidationConfig {
+            fast_sanitize_above: Some(20),
+            ..Default::default()
+        };
+        let long = "<div onclick=\"x()\">a long question<script>x()</script>";
+        let result = validate_card(&card(CardType::Basic, long, "<div>short"), &config);
+        // the long front isn't repaired, but the short back is
+        assert_eq!(result.card.front, "<div>a long question");
+        assert_eq!(result.card.back, "<div>short</div>");
+    }
+
+    #[test]
+    fn empty_back_can_be_allowed_per_type() {
+        let basic = card(CardType::Basic, "<img src=a.jpg>", "");
+        let reversed = card(CardType::BasicReversed, "Q", "");
+        let config = ValidationConfig {
+            allow_empty_back: vec![CardType::Basic],
+            ..Default::default()
+        };
+        assert!(!validate_card(&basic, &ValidationConfig::default()).valid);
+        assert!(validate_card(&basic, &config).valid);
+        assert!(!validate_card(&reversed, &config).valid);
+    }
+
+    #[test]
+    fn image_alt_text_revealing_the_answer_is_reported() {
+        let leaking = card(
+            CardType::Basic,
+            r#"Which organ is this? <img src="a.jpg" alt="The human heart">"#,
+            "Heart",
+        );
+        let result = validate_card(&leaking, &ValidationConfig::default());
+        assert!(result.issues.iter().any(|i| i.message.contains("alt text")));
+
+        let safe = card(
+            CardType::Basic,
+            r#"Which organ is this? <img src="a.jpg" alt="An organ">"#,
+            "Heart",
+        );
+        let result = validate_card(&safe, &ValidationConfig::default());
+        assert!(!result.issues.iter().any(|i| i.message.contains("alt text")));
+
+        // short answers, and answers that are only part of a word, aren't
+        // reported
+        for (alt, back) in [
+            ("A diagram of figure 1", "a"),
+            ("A diagram of figure 1", "1"),
+            ("Blood cells", "cell"),
+        ] {
+            let safe = card(
+                CardType::Basic,
+                &format!(r#"What is shown? <img src="a.jpg" alt="{alt}">"#),
+                back,
+            );
+            let result = validate_card(&safe, &ValidationConfig::default());
+            assert!(!result.issues.iter().any(|i| i.message.contains("alt text")));
+        }
+    }
+
+    #[test]
+    fn paraphrased_cards_are_found() {
+        let mut cards = vec![
+            card(CardType::Basic, "What is the capital of France?", "Paris"),
+            card(
+                CardType::Basic,
+                "What's the capital city of <b>France</b>?",
+                "Paris",
+            ),
+            card(
+                CardType::Basic,
+                "What is mitochondria?",
+                "The powerhouse of the cell",
+            ),
+        ];
+        cards[1].id = "2".to_string();
+        cards[2].id = "3".to_string();
+        let pairs = find_similar_pairs(&cards, 0.6);
+        assert_eq!(pairs.len(), 1);
+        let (first, second, score) = pairs[0];
+        assert_eq!((first, second), (0, 1));
+        assert!((score - 6.0 / 9.0).abs() < 0.001);
+
+        let mut results: Vec<ValidationResult> = cards
+            .iter()
+            .map(|card| validate_card(card, &ValidationConfig::default()))
+            .collect();
+        flag_similar_cards(&mut results, 0.6);
+        assert!(results[0].issues[0].message.contains("card '2'"));
+        assert!(results[1].issues[0].message.contains("card '1'"));
+        assert!(results[2].issues.is_empty());
+    }
+
+    #[test]
+    fn batch_validation_reports_progress_and_can_be_cancelled() {
+        let cards = vec![card(CardType::Basic, "Q", "A"); 40];
+        let state = Arc::new(Mutex::new(ProgressState::default()));
+
+        let mut progress = ThrottlingProgressHandler::new(state.clone());
+        let batch =
+            validate_cards_with_progress(&cards, &Default::default(), &mut progress).unwrap();
+        assert!(!batch.cancelled);
+        assert_eq!(batch.results.len(), 40);
+        assert!(matches!(
+            state.lock().unwrap().last_progress,
+            Some(Progress::Import(ImportProgress::Notes(40)))
+        ));
+
+        // the first update isn't throttled, so the request is seen at once
+        let mut progress = ThrottlingProgressHandler::new(state.clone());
+        state.lock().unwrap().want_abort = true;
+        let batch =
+            validate_cards_with_progress(&cards, &Default::default(), &mut progress).unwrap();
+        assert!(batch.cancelled);
+        assert_eq!(batch.results.len(), 17);
+    }
+
+    #[test]
+    fn tags_differing_only_by_case_are_reported() {
+        let mut cards = vec![
+            card(CardType::Basic, "Q1", "A1"),
+            card(CardType::Basic, "Q2", "A2"),
+        ];
+        cards[0].suggested_tags = vec!["Biology".to_string(), "cells".to_string()];
+        cards[1].suggested_tags = vec!["biology".to_string(), "cells".to_string()];
+        assert_eq!(
+            tag_case_conflicts(&cards),
+            [(
+                "biology".to_string(),
+                vec!["Biology".to_string(), "biology".to_string()]
+            )]
+        );
+    }
+
+    #[test]
+    fn empty_paragraphs_around_clozes_are_removed() {
+        let cloze = card(
+            CardType::Cloze,
+            "<p></p><p>{{c1::Paris}} is the capital of France</p><p> </p>",
+            "",
+        );
+        let result = validate_card(&cloze, &ValidationConfig::default());
+        assert_eq!(
+            result.card.front,
+            "<p>{{c1::Paris}} is the capital of France</p>"
+        );
+        assert!(result.issues.is_empty());
+    }
+}
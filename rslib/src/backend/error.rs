@@ -37,6 +37,7 @@ impl AnkiError {
             AnkiError::DatabaseCheckRequired => Kind::InvalidInput,
             AnkiError::CustomStudyError { .. } => Kind::CustomStudyError,
             AnkiError::ImportError { .. } => Kind::ImportError,
+            AnkiError::AiFlashcardsError { .. } => Kind::InvalidInput,
             AnkiError::FileIoError { .. } => Kind::IoError,
             AnkiError::MediaCheckRequired => Kind::InvalidInput,
             AnkiError::InvalidId => Kind::InvalidInput,
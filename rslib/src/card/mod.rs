@@ -396,20 +396,29 @@ impl Collection {
         let flag = flag as u8;
 
         let usn = self.usn()?;
-        self.transact(Op::SetFlag, |col| {
-            let mut count = 0;
-            for mut card in col.all_cards_for_ids(cards, false)? {
-                let original = card.clone();
-                if card.set_flag(flag) {
-                    // To avoid having to rebuild the study queues, we mark the card as requiring
-                    // a sync, but do not change its modification time.
-                    card.usn = usn;
-                    col.update_card_undoable(&mut card, original)?;
-                    count += 1;
-                }
+        self.transact(Op::SetFlag, |col| col.set_card_flag_inner(cards, flag, usn))
+    }
+
+    /// Set the flag of the provided cards, returning the number of cards that
+    /// changed. Must be called within a transaction.
+    pub(crate) fn set_card_flag_inner(
+        &mut self,
+        cards: &[CardId],
+        flag: u8,
+        usn: Usn,
+    ) -> Result<usize> {
+        let mut count = 0;
+        for mut card in self.all_cards_for_ids(cards, false)? {
+            let original = card.clone();
+            if card.set_flag(flag) {
+                // To avoid having to rebuild the study queues, we mark the card as requiring
+                // a sync, but do not change its modification time.
+                card.usn = usn;
+                self.update_card_undoable(&mut card, original)?;
+                count += 1;
             }
-            Ok(count)
-        })
+        }
+        Ok(count)
     }
 
     /// Get deck config for the given card. If missing, return default values.
@@ -29,6 +29,7 @@ pub use self::invalid_input::InvalidInputError;
 pub use self::invalid_input::OrInvalid;
 pub use self::not_found::NotFoundError;
 pub use self::not_found::OrNotFound;
+use crate::ai_flashcards::AiFlashcardsError;
 use crate::import_export::ImportError;
 use crate::links::HelpPage;
 
@@ -105,6 +106,10 @@ pub enum AnkiError {
     ImportError {
         source: ImportError,
     },
+    #[snafu(context(false))]
+    AiFlashcardsError {
+        source: AiFlashcardsError,
+    },
     InvalidId,
     #[cfg(windows)]
     #[snafu(context(false))]
@@ -161,6 +166,7 @@ impl AnkiError {
             AnkiError::MediaCheckRequired => tr.errors_please_check_media().into(),
             AnkiError::CustomStudyError { source } => source.message(tr),
             AnkiError::ImportError { source } => source.message(tr),
+            AnkiError::AiFlashcardsError { source } => source.message(tr),
             AnkiError::Deleted => tr.browsing_row_deleted().into(),
             AnkiError::InvalidId => tr.errors_please_check_database().into(),
             AnkiError::JsonError { .. }
@@ -328,7 +328,7 @@ pub(crate) fn base91_u64() -> String {
     anki_base91(rand::random())
 }
 
-fn anki_base91(n: u64) -> String {
+pub(crate) fn anki_base91(n: u64) -> String {
     to_base_n(
         n,
         b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ\
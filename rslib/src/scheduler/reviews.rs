@@ -127,42 +127,8 @@ impl Collection {
         context: Option<StringKey>,
     ) -> Result<OpOutput<()>> {
         let spec = parse_due_date_str(days)?;
-        let usn = self.usn()?;
-        let today = self.timing_today()?.days_elapsed;
-        let next_day_start = self.timing_today()?.next_day_at.0;
-        let mut rng = rand::rng();
-        let distribution = Uniform::new_inclusive(spec.min, spec.max).unwrap();
-        let mut decks_initial_ease: HashMap<DeckId, f32> = HashMap::new();
         self.transact(Op::SetDueDate, |col| {
-            for mut card in col.all_cards_for_ids(cids, false)? {
-                let deck_id = card.original_deck_id.or(card.deck_id);
-                let ease_factor = match decks_initial_ease.get(&deck_id) {
-                    Some(ease) => *ease,
-                    None => {
-                        let deck = col.get_deck(deck_id)?.or_not_found(deck_id)?;
-                        let config_id = deck.config_id().or_invalid("home deck is filtered")?;
-                        let ease = col
-                            .get_deck_config(config_id, true)?
-                            // just for compiler; get_deck_config() is guaranteed to return a value
-                            .unwrap_or_default()
-                            .inner
-                            .initial_ease;
-                        decks_initial_ease.insert(deck_id, ease);
-                        ease
-                    }
-                };
-                let original = card.clone();
-                let days_from_today = distribution.sample(&mut rng);
-                card.set_due_date(
-                    today,
-                    next_day_start,
-                    days_from_today,
-                    ease_factor,
-                    spec.force_reset,
-                );
-                col.log_manually_scheduled_review(&card, original.interval, usn)?;
-                col.update_card_inner(&mut card, original, usn)?;
-            }
+            col.set_due_date_inner(cids, &spec)?;
             if let Some(key) = context {
                 col.set_config_string_inner(key, days)?;
             }
@@ -170,6 +136,51 @@ impl Collection {
         })
     }
 
+    /// Like [Collection::set_due_date], but must be called within a
+    /// transaction.
+    pub(crate) fn set_due_date_inner(
+        &mut self,
+        cids: &[CardId],
+        spec: &DueDateSpecifier,
+    ) -> Result<()> {
+        let usn = self.usn()?;
+        let today = self.timing_today()?.days_elapsed;
+        let next_day_start = self.timing_today()?.next_day_at.0;
+        let mut rng = rand::rng();
+        let distribution = Uniform::new_inclusive(spec.min, spec.max).unwrap();
+        let mut decks_initial_ease: HashMap<DeckId, f32> = HashMap::new();
+        for mut card in self.all_cards_for_ids(cids, false)? {
+            let deck_id = card.original_deck_id.or(card.deck_id);
+            let ease_factor = match decks_initial_ease.get(&deck_id) {
+                Some(ease) => *ease,
+                None => {
+                    let deck = self.get_deck(deck_id)?.or_not_found(deck_id)?;
+                    let config_id = deck.config_id().or_invalid("home deck is filtered")?;
+                    let ease = self
+                        .get_deck_config(config_id, true)?
+                        // just for compiler; get_deck_config() is guaranteed to return a value
+                        .unwrap_or_default()
+                        .inner
+                        .initial_ease;
+                    decks_initial_ease.insert(deck_id, ease);
+                    ease
+                }
+            };
+            let original = card.clone();
+            let days_from_today = distribution.sample(&mut rng);
+            card.set_due_date(
+                today,
+                next_day_start,
+                days_from_today,
+                ease_factor,
+                spec.force_reset,
+            );
+            self.log_manually_scheduled_review(&card, original.interval, usn)?;
+            self.update_card_inner(&mut card, original, usn)?;
+        }
+        Ok(())
+    }
+
     pub fn grade_now(&mut self, cids: &[CardId], rating: i32) -> Result<OpOutput<()>> {
         self.transact(Op::GradeNow, |col| {
             for &card_id in cids {
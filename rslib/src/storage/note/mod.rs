@@ -178,6 +178,20 @@ impl super::SqliteStorage {
             .collect()
     }
 
+    /// Returns [(nid, ntid, field 0)] of notes with a card in the deck.
+    pub(crate) fn first_fields_of_notes_in_deck(
+        &self,
+        did: DeckId,
+    ) -> Result<Vec<(NoteId, NotetypeId, String)>> {
+        self.db
+            .prepare(
+                "select id, mid, field_at_index(flds, 0) from notes
+                where id in (select nid from cards where did=?)",
+            )?
+            .query_and_then([did], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?
+            .collect()
+    }
+
     /// Returns [(nid, field 0)] of notes with the same checksum.
     /// The caller should strip the fields and compare to see if they actually
     /// match.
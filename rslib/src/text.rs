@@ -373,7 +373,6 @@ pub fn contains_media_tag(html: &str) -> bool {
     HTML_MEDIA_TAGS.is_match(html)
 }
 
-#[allow(dead_code)]
 pub(crate) fn sanitize_html(html: &str) -> String {
     ammonia::clean(html)
 }